@@ -0,0 +1,31 @@
+use std::sync::Arc;
+use chrono::Duration;
+use ocd_datalake_rs::{Datalake, DatalakeSetting};
+use ocd_datalake_rs::credentials::EnvCredentialProvider;
+use ocd_datalake_rs::advanced_search::AdvancedQuery;
+use ocd_datalake_rs::atom::AtomType;
+use ocd_datalake_rs::query::{Filter, Query};
+use ocd_datalake_rs::threat::ThreatType;
+
+fn main() {
+    let dtl = Datalake::builder()
+        .credential_provider(Arc::new(EnvCredentialProvider::new()))  // OCD_DTL_RS_USERNAME & PASSWORD or LONGTERM_TOKEN
+        .setting(DatalakeSetting::preprod())
+        .build()
+        .unwrap();
+
+    let query = Query::and([
+        Filter::atom_type(AtomType::Ip),
+        Filter::score_gte(ThreatType::Malware, 60),
+        Filter::last_updated_within(Duration::days(1)),
+    ]);
+    let query_hash = dtl.get_query_hash(&query.to_value()).expect("API Error");
+    println!("query hash, usable with bulk_search: {query_hash}");
+
+    for page in dtl.advanced_search_pages(AdvancedQuery::Hash(query_hash), 100).take(3) {
+        match page {
+            Ok(threats) => threats.iter().for_each(|threat| println!("{} {:?}", threat.hashkey, threat.atom_value)),
+            Err(err) => println!("{err}"),
+        }
+    }
+}
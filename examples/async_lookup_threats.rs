@@ -0,0 +1,22 @@
+use std::sync::Arc;
+use ocd_datalake_rs::{AsyncDatalake, DatalakeSetting};
+use ocd_datalake_rs::credentials::EnvCredentialProvider;
+use ocd_datalake_rs::atom::HashType;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let dtl = AsyncDatalake::builder()
+        .credential_provider(Arc::new(EnvCredentialProvider::new()))  // OCD_DTL_RS_USERNAME & PASSWORD or LONGTERM_TOKEN
+        .setting(DatalakeSetting::preprod())
+        .build_async()
+        .unwrap();
+
+    let atom_values: Vec<String> = [
+        "enus.patch.battle.net",  // domain
+        "fde26bc70eeb45d7db5c18f91739f263c96262ea9fe254c59d993dc44b248774",  // file
+    ].iter().map(|x| x.to_string()).collect();
+    match dtl.bulk_lookup(atom_values, HashType::File).await {
+        Ok(csv_result) => println!("{csv_result}"),
+        Err(err) => println!("{err}"),
+    }
+}
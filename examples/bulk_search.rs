@@ -1,49 +1,50 @@
-use std::{env, io, thread};
-use std::sync::mpsc;
-use std::sync::mpsc::TryRecvError;
-use std::time::{Duration, Instant};
-use std::io::Write;
-use ocd_datalake_rs::{ATOM_VALUE_QUERY_FIELD, Datalake, DatalakeSetting};
+use std::env;
+use std::io::{self, Write};
+use std::time::Instant;
+use chrono::{Duration, Utc};
+use std::sync::Arc;
+use ocd_datalake_rs::{Datalake, DatalakeSetting};
+use ocd_datalake_rs::credentials::EnvCredentialProvider;
+use ocd_datalake_rs::bulk_search::{list_bulk_search_tasks, BulkSearchHandle, BulkSearchTaskFilter, QueryField, State};
 
 fn main() {
-    let username = env::var("OCD_DTL_RS_USERNAME").ok();
-    let password = env::var("OCD_DTL_RS_PASSWORD").ok();
-    let longterm_token = env::var("OCD_DTL_RS_LONGTERM_TOKEN").ok();
     let mut preprod_setting = DatalakeSetting::preprod();
     preprod_setting.bulk_search_timeout_sec = 10 * 60;  // Wait at max 10 minutes before timeout
-    let mut dtl = Datalake::new(
-        username,
-        password,
-        longterm_token,
-        preprod_setting,
-    ).unwrap();
+    let dtl = Datalake::builder()
+        .credential_provider(Arc::new(EnvCredentialProvider::new()))  // OCD_DTL_RS_USERNAME & PASSWORD or LONGTERM_TOKEN
+        .setting(preprod_setting)
+        .build()
+        .unwrap();
 
     let query_hash = "fbecd3d440a7d439a2a1fd996c703a8d".to_string();  // IPs updated the last day 
     // other examples : 685596bc5cbb5e8d7dc553157f26d3e1 (13 results), 3e20613adf80978e590bfdfafdb31aa1 (9971 results)
+    // a query hash can also be generated from a query body with get_query_hash, see the advanced_search example
+
+    // Resume the task of a previous run if its uuid is given, or reuse a task already done for the same query and fields
+    // whose result can still be downloaded, instead of submitting the bulk search again
+    let query_fields: Vec<String> = vec![QueryField::AtomValue.into()];
+    let done_tasks_filter = BulkSearchTaskFilter {
+        states: vec![State::DONE],
+        query_hash: Some(query_hash.clone()),
+        query_fields: Some(query_fields.clone()),
+        file_available_at: Some(Utc::now() + Duration::minutes(10)),  // Leave time for the download
+        ..Default::default()
+    };
+    let done_task = list_bulk_search_tasks(&dtl, &done_tasks_filter).expect("API Error").into_iter().next();
+    let handle = match (env::var("OCD_DTL_RS_BULK_SEARCH_TASK_UUID"), done_task) {
+        (Ok(task_uuid), _) => BulkSearchHandle::from_uuid(&dtl, task_uuid),
+        (Err(_), Some(task)) => BulkSearchHandle::from_uuid(&dtl, task.uuid),
+        (Err(_), None) => dtl.start_bulk_search(query_hash, query_fields).expect("API Error"),
+    };
+    println!("Bulk search task uuid: {}", handle.task_uuid());
 
-    let (sender, receiver) = mpsc::channel();
     let start_time = Instant::now();
-    let ui_thread = thread::spawn(move || loop {
-        thread::sleep(Duration::from_millis(500));
-        print!("{esc}c", esc = 27 as char);  // Clear output
-        match receiver.try_recv() {
-            Ok(_) | Err(TryRecvError::Disconnected) => {
-                println!("Bulk search process is done ✅");
-                thread::sleep(Duration::from_secs(1));
-                break;
-            }
-            Err(TryRecvError::Empty) => {
-                print!("Waiting for bulk search completion since {}s", start_time.elapsed().as_secs());
-                io::stdout().flush().unwrap();
-            }
-        }
+    let res = handle.wait_with(|task| {
+        let progress = task.progress.map(|progress| format!(" ({progress:.0}%)")).unwrap_or_default();
+        print!("\rBulk search is {}{progress} since {}s", task.state, start_time.elapsed().as_secs());
+        io::stdout().flush().unwrap();
     });
-    let bulk_search_thread = thread::spawn(move ||
-        dtl.bulk_search(query_hash, vec![ATOM_VALUE_QUERY_FIELD.to_string()])
-    );
-    let res = bulk_search_thread.join().expect("Thread failed");
-    sender.send(()).unwrap();  // Send the stop signal to ui thread
-    ui_thread.join().unwrap();
+    println!();
 
     match res {
         Ok(atom_values) => println!("{atom_values}"),
@@ -52,4 +53,4 @@ fn main() {
             println!("{err:?}");
         }
     }
-}
\ No newline at end of file
+}
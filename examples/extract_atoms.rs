@@ -1,21 +1,19 @@
-use std::env;
+use std::sync::Arc;
 use ocd_datalake_rs::{Datalake, DatalakeSetting};
+use ocd_datalake_rs::credentials::EnvCredentialProvider;
+use ocd_datalake_rs::atom::HashType;
 
 fn main() {
-    let username = env::var("OCD_DTL_RS_USERNAME").ok();
-    let password = env::var("OCD_DTL_RS_PASSWORD").ok();
-    let longterm_token = env::var("OCD_DTL_RS_LONGTERM_TOKEN").ok();
-    let mut dtl = Datalake::new(
-        username,
-        password,
-        longterm_token,
-        DatalakeSetting::preprod()
-    ).unwrap();
-    let atom_values: Vec<String> = vec!["domain.com", "4.4.4.4", "1.1.1.1", "7ba226e0538c234638beae091ba53f0282fa9fb6"]
+    let dtl = Datalake::builder()
+        .credential_provider(Arc::new(EnvCredentialProvider::new()))  // OCD_DTL_RS_USERNAME & PASSWORD or LONGTERM_TOKEN
+        .setting(DatalakeSetting::preprod())
+        .build()
+        .unwrap();
+    let atom_values: Vec<String> = ["domain.com", "4.4.4.4", "1.1.1.1", "7ba226e0538c234638beae091ba53f0282fa9fb6"]
         .iter()
         .map(|x| x.to_string())
         .collect();
-    let extracted = dtl.extract_atom_type(&atom_values, "certificate").expect("API Error");
+    let extracted = dtl.extract_atom_type(&atom_values, HashType::Certificate).expect("API Error");
     for (atom_value, atom_type) in extracted {
         println!("{} is of type {}", atom_value, atom_type);
     }
@@ -1,23 +1,23 @@
-use std::env;
+use std::sync::Arc;
 use ocd_datalake_rs::{Datalake, DatalakeSetting};
+use ocd_datalake_rs::credentials::{EnvCredentialProvider, FileCredentialProvider};
+use ocd_datalake_rs::atom::HashType;
 
 fn main() {
-    let username = env::var("OCD_DTL_RS_USERNAME").ok();
-    let password = env::var("OCD_DTL_RS_PASSWORD").ok();
-    let longterm_token = env::var("OCD_DTL_RS_LONGTERM_TOKEN").ok();
-    let mut dtl = Datalake::new(
-        username,
-        password,
-        longterm_token,
-        DatalakeSetting::preprod(),
-    ).unwrap();
+    let dtl = Datalake::builder()
+        .credential_provider(Arc::new(FileCredentialProvider::new("/run/secrets/ocd_dtl_rs")))  // Docker secrets first
+        .credential_provider(Arc::new(EnvCredentialProvider::new()))  // then OCD_DTL_RS_USERNAME & PASSWORD or LONGTERM_TOKEN
+        .setting(DatalakeSetting::preprod())
+        .build()
+        .unwrap();
+    println!("Credentials from the {}", dtl.credentials_source());
 
-    let atom_values: Vec<String> = vec![
+    let atom_values: Vec<String> = [
         "enus.patch.battle.net",  // domain
         "fde26bc70eeb45d7db5c18f91739f263c96262ea9fe254c59d993dc44b248774",  // file
         "7ba226e0538c234638beae091ba53f0282fa9fb6",  // certificate
     ].iter().map(|x| x.to_string()).collect();
-    let csv_result: String = match dtl.bulk_lookup(atom_values, "certificate") {
+    let csv_result: String = match dtl.bulk_lookup(atom_values, HashType::Certificate) {
         Ok(result) => { result }
         Err(err) => {
             println!("{err}");  // User readable error
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::{ApiError, AsyncDatalake, Datalake, DatalakeError, DetailedError};
+use crate::threat::Threat;
+
+const QUERY_HASH_LIMIT: u64 = 1;  // Smallest page, only the query_hash of the response is used
+
+/// Advanced search query: either its body, the JSON AND/OR tree of filters, or the hash of an existing query
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdvancedQuery {
+    Body(Value),
+    Hash(String),
+}
+
+/// A page of threats matching an advanced search query
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AdvancedSearchResult {
+    pub count: u64,  // Total number of threats matching the query
+    pub query_hash: String,
+    pub results: Vec<Threat>,
+}
+
+/// Iterator over all the pages of an advanced search, see [Datalake::advanced_search_pages]
+pub struct AdvancedSearchPages<'a> {
+    dtl: &'a Datalake,
+    pages: AsyncAdvancedSearchPages<'a>,
+}
+
+impl Iterator for AdvancedSearchPages<'_> {
+    type Item = Result<Vec<Threat>, DatalakeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.dtl.runtime.block_on(self.pages.next_page())
+    }
+}
+
+/// All the pages of an advanced search, see [AsyncDatalake::advanced_search_pages]
+pub struct AsyncAdvancedSearchPages<'a> {
+    dtl: &'a AsyncDatalake,
+    query: AdvancedQuery,
+    page_size: u64,
+    offset: u64,
+    count: Option<u64>,
+    is_done: bool,
+}
+
+impl AsyncAdvancedSearchPages<'_> {
+    /// Return the next page of threats, or None once every page was returned or after an error
+    pub async fn next_page(&mut self) -> Option<Result<Vec<Threat>, DatalakeError>> {
+        if self.is_done || self.count.is_some_and(|count| self.offset >= count) {
+            return None;
+        }
+        match self.dtl.advanced_search(&self.query, self.page_size, self.offset).await {
+            Ok(result) => {
+                self.count = Some(result.count);
+                self.query = AdvancedQuery::Hash(result.query_hash);  // Next pages reuse the query already sent
+                self.offset += self.page_size;
+                if result.results.is_empty() {
+                    self.is_done = true;
+                    return None;
+                }
+                Some(Ok(result.results))
+            }
+            Err(err) => {
+                self.is_done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl Datalake {
+    /// Return the query_hash of a query body, to be used with [Datalake::bulk_search]
+    pub fn get_query_hash(&self, query_body: &Value) -> Result<String, DatalakeError> {
+        self.runtime.block_on(self.inner.get_query_hash(query_body))
+    }
+
+    /// Run a query synchronously and return a single page of threats
+    pub fn advanced_search(&self, query: &AdvancedQuery, limit: u64, offset: u64) -> Result<AdvancedSearchResult, DatalakeError> {
+        self.runtime.block_on(self.inner.advanced_search(query, limit, offset))
+    }
+
+    /// Iterate over all the threats matching a query, page by page
+    pub fn advanced_search_pages(&self, query: AdvancedQuery, page_size: u64) -> AdvancedSearchPages<'_> {
+        AdvancedSearchPages {
+            dtl: self,
+            pages: self.inner.advanced_search_pages(query, page_size),
+        }
+    }
+}
+
+impl AsyncDatalake {
+    /// Return the query_hash of a query body, to be used with [AsyncDatalake::bulk_search]
+    pub async fn get_query_hash(&self, query_body: &Value) -> Result<String, DatalakeError> {
+        let query = AdvancedQuery::Body(query_body.clone());
+        Ok(self.advanced_search(&query, QUERY_HASH_LIMIT, 0).await?.query_hash)
+    }
+
+    /// Run a query synchronously and return a single page of threats
+    pub async fn advanced_search(&self, query: &AdvancedQuery, limit: u64, offset: u64) -> Result<AdvancedSearchResult, DatalakeError> {
+        let routes = self.settings.routes();
+        let (url, request) = match query {
+            AdvancedQuery::Body(query_body) => {
+                let url = routes.advanced_search.clone();
+                let body = json!({
+                    "query_body": query_body,
+                    "limit": limit,
+                    "offset": offset,
+                });
+                let request = self.client.post(&url).json(&body);
+                (url, request)
+            }
+            AdvancedQuery::Hash(query_hash) => {
+                let url = routes.advanced_search_hash.replace("{query_hash}", query_hash);
+                let request = self.client.get(&url).query(&[("limit", limit), ("offset", offset)]);
+                (url, request)
+            }
+        };
+        let request = request.header("Accept", "application/json");
+        let resp = self.run_with_authorization_token(request).await?;
+        let status_code = resp.status();
+        if resp.error_for_status_ref().is_err() {
+            let err = DetailedError {
+                summary: format!("advanced search returned error code {status_code}"),
+                api_url: Some(url),
+                api_response: resp.text().await.ok(),
+                api_status_code: Some(status_code),
+            };
+            return Err(ApiError(err));
+        }
+        let json_response = resp.json::<Value>().await?;
+        let api_response = Some(json_response.to_string());
+        match serde_json::from_value::<AdvancedSearchResult>(json_response) {
+            Ok(result) => Ok(result),
+            Err(_) => {
+                let summary = "advanced search API response not as expected".to_string();
+                Err(ApiError(DetailedError { summary, api_url: Some(url), api_response, api_status_code: Some(status_code) }))
+            }
+        }
+    }
+
+    /// All the threats matching a query, page by page
+    pub fn advanced_search_pages(&self, query: AdvancedQuery, page_size: u64) -> AsyncAdvancedSearchPages<'_> {
+        AsyncAdvancedSearchPages {
+            dtl: self,
+            query,
+            page_size: page_size.max(1),
+            offset: 0,
+            count: None,
+            is_done: false,
+        }
+    }
+}
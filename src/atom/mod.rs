@@ -0,0 +1,130 @@
+use std::str::FromStr;
+use strum_macros::{EnumString, IntoStaticStr};
+use crate::{DatalakeError, DetailedError};
+use crate::DatalakeError::ParseError;
+
+/// Type of an atom, as named by the Datalake API
+///
+/// Types not known by this library are kept as `Unknown`, so new API types do not break parsing.
+#[derive(Debug, EnumString, IntoStaticStr, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[strum(serialize_all = "snake_case")]
+pub enum AtomType {
+    Apk,
+    As,
+    Cc,
+    Certificate,
+    Crypto,
+    Domain,
+    Email,
+    File,
+    Fqdn,
+    Iban,
+    Ip,
+    IpRange,
+    Jarm,
+    Paste,
+    PhoneNumber,
+    Regkey,
+    Ssdeep,
+    Url,
+    #[strum(default)]
+    Unknown(String),
+}
+
+/// Atom type hashes are treated like, when the atom type is guessed from the atom value
+///
+/// Parsing is strict so a typo is caught early, `Unknown` is only used when deserializing an API response.
+#[derive(Debug, IntoStaticStr, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[strum(serialize_all = "snake_case")]
+pub enum HashType {
+    File,
+    Certificate,
+    Ssdeep,
+    Unknown(String),
+}
+
+impl HashType {
+    const KNOWN: [HashType; 3] = [HashType::File, HashType::Certificate, HashType::Ssdeep];
+}
+
+impl FromStr for HashType {
+    type Err = DatalakeError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        HashType::KNOWN.into_iter()
+            .find(|hash_type| <&str>::from(hash_type) == name)
+            .ok_or_else(|| ParseError(DetailedError::new(format!("unknown hash type: {name}, expected file, certificate or ssdeep"))))
+    }
+}
+
+/// Display and serde use the API name, or the raw value for `Unknown`
+macro_rules! impl_api_name {
+    ($enum_type:ident) => {
+        impl std::fmt::Display for $enum_type {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    $enum_type::Unknown(value) => f.pad(value),
+                    known => f.pad(known.into()),
+                }
+            }
+        }
+
+        impl serde::Serialize for $enum_type {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $enum_type {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+                Ok(<$enum_type as std::str::FromStr>::from_str(&value).unwrap_or($enum_type::Unknown(value)))
+            }
+        }
+    };
+}
+pub(crate) use impl_api_name;
+
+impl_api_name!(AtomType);
+impl_api_name!(HashType);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use serde_json::json;
+    use crate::atom::{AtomType, HashType};
+
+    #[test]
+    fn test_atom_type_names() {
+        assert_eq!(AtomType::IpRange.to_string(), "ip_range");
+        assert_eq!(AtomType::PhoneNumber.to_string(), "phone_number");
+        assert_eq!(AtomType::from_str("as").unwrap(), AtomType::As);
+        assert_eq!(HashType::from_str("ssdeep").unwrap(), HashType::Ssdeep);
+    }
+
+    #[test]
+    fn test_unknown_types_are_kept() {
+        let atom_type = AtomType::from_str("new_type").unwrap();
+
+        assert_eq!(atom_type, AtomType::Unknown("new_type".to_string()));
+        assert_eq!(atom_type.to_string(), "new_type");
+        let hash_type: HashType = serde_json::from_value(json!("sha-256")).unwrap();
+        assert_eq!(hash_type, HashType::Unknown("sha-256".to_string()));
+        assert_eq!(hash_type.to_string(), "sha-256");
+    }
+
+    #[test]
+    fn test_hash_type_parsing_is_strict() {
+        let err = HashType::from_str("sha-256").unwrap_err();
+        assert_eq!(err.to_string(), "Parse Error unknown hash type: sha-256, expected file, certificate or ssdeep");
+        assert!(HashType::from_str("unknown").is_err());
+    }
+
+    #[test]
+    fn test_atom_type_serde() {
+        assert_eq!(json!(AtomType::IpRange), json!("ip_range"));
+        assert_eq!(json!(AtomType::Unknown("new_type".to_string())), json!("new_type"));
+        let atom_types: Vec<AtomType> = serde_json::from_value(json!(["fqdn", "new_type"])).unwrap();
+        assert_eq!(atom_types, [AtomType::Fqdn, AtomType::Unknown("new_type".to_string())]);
+    }
+}
@@ -0,0 +1,434 @@
+use std::env;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use log::{debug, info};
+use reqwest::{Certificate, Client, ClientBuilder, NoProxy, Proxy};
+use tokio::runtime::Runtime;
+use tokio::sync::RwLock;
+use crate::{AsyncDatalake, Datalake, DatalakeError, DatalakeSetting, DetailedError, ProxySetting};
+use crate::credentials::{CredentialProvider, Credentials};
+use crate::polling::{Clock, SystemClock};
+use crate::secret::{Secret, REDACTED};
+use crate::token_store::TokenStore;
+use crate::DatalakeError::{AuthenticationError, ClientError, ProxyError, UnexpectedLibError};
+
+const DEFAULT_HTTP_TIMEOUT: u64 = 30;  // Same default as reqwest::blocking
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+const PROXY_SCHEMES: [&str; 4] = ["http://", "https://", "socks5://", "socks5h://"];
+const BUILDER_CREDENTIALS_SOURCE: &str = "builder";
+const BLOCKING_RUNTIME_WORKER_THREADS: usize = 2;
+
+/// Runtime of every blocking client, started with the first one and never dropped
+static BLOCKING_RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// Each calling thread drives its own request with `block_on`, the few workers only run the connection tasks of reqwest
+fn blocking_runtime() -> Result<&'static Runtime, DatalakeError> {
+    if let Some(runtime) = BLOCKING_RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(BLOCKING_RUNTIME_WORKER_THREADS)
+        .thread_name("ocd-datalake-rs")
+        .enable_all()
+        .build()
+        .map_err(|e| UnexpectedLibError(DetailedError::new(format!("Failed to start the tokio runtime : {e}"))))?;
+    Ok(BLOCKING_RUNTIME.get_or_init(|| runtime))  // A runtime started concurrently by another thread is dropped here
+}
+
+/// Fluent configuration of a [Datalake] or an [AsyncDatalake]
+///
+/// ```no_run
+/// use ocd_datalake_rs::{Datalake, DatalakeSetting};
+///
+/// let dtl = Datalake::builder()
+///     .credentials("username".to_string(), "password".to_string())
+///     .setting(DatalakeSetting::preprod())
+///     .timeout(std::time::Duration::from_secs(60))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct DatalakeBuilder {
+    setting: Option<DatalakeSetting>,
+    username: Option<String>,
+    password: Option<Secret>,
+    longterm_token: Option<Secret>,
+    http_client: Option<Client>,
+    timeout: Option<Duration>,
+    user_agent: Option<String>,
+    root_certificates: Vec<Certificate>,
+    accept_invalid_certs: bool,
+    clock: Option<Arc<dyn Clock>>,
+    token_store: Option<Arc<dyn TokenStore>>,
+    credential_providers: Vec<Arc<dyn CredentialProvider>>,
+}
+
+impl fmt::Debug for DatalakeBuilder {
+    /// Credentials are redacted, the username included, like for [AsyncDatalake]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DatalakeBuilder")
+            .field("setting", &self.setting)
+            .field("username", &self.username.as_ref().map(|_| REDACTED))
+            .field("password", &self.password)
+            .field("longterm_token", &self.longterm_token)
+            .field("http_client", &self.http_client)
+            .field("timeout", &self.timeout)
+            .field("user_agent", &self.user_agent)
+            .field("root_certificates", &self.root_certificates)
+            .field("accept_invalid_certs", &self.accept_invalid_certs)
+            .field("clock", &self.clock)
+            .field("token_store", &self.token_store)
+            .field("credential_providers", &self.credential_providers)
+            .finish()
+    }
+}
+
+impl DatalakeBuilder {
+    pub fn credentials(mut self, username: String, password: String) -> Self {
+        self.username = Some(username);
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Overwrites the credentials, no login is made with a long-term token
+    pub fn longterm_token(mut self, longterm_token: String) -> Self {
+        self.longterm_token = Some(longterm_token.into());
+        self
+    }
+
+    /// Defaults to [DatalakeSetting::prod]
+    pub fn setting(mut self, setting: DatalakeSetting) -> Self {
+        self.setting = Some(setting);
+        self
+    }
+
+    /// Use the given client as is, the proxy, timeout, user agent and TLS options are then ignored
+    pub fn http_client(mut self, http_client: Client) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// Default timeout of every request, 30 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: String) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    /// Trust an additional root certificate, on top of the system's native ones
+    pub fn root_certificate(mut self, certificate: Certificate) -> Self {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// > **Warning** disables TLS certificate validation, only use it for testing
+    pub fn danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    /// Clock of the bulk search polling, [SystemClock] by default
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Reuse the session saved by previous clients with the same base_url and username, and save the new ones
+    pub fn token_store(mut self, token_store: Arc<dyn TokenStore>) -> Self {
+        self.token_store = Some(token_store);
+        self
+    }
+
+    /// Providers are tried in the order they are added, once no credentials were given to the builder itself
+    pub fn credential_provider(mut self, credential_provider: Arc<dyn CredentialProvider>) -> Self {
+        self.credential_providers.push(credential_provider);
+        self
+    }
+
+    pub fn build(self) -> Result<Datalake, DatalakeError> {
+        let inner = self.build_async()?;
+        Ok(Datalake {
+            inner,
+            runtime: blocking_runtime()?,
+        })
+    }
+
+    pub fn build_async(mut self) -> Result<AsyncDatalake, DatalakeError> {
+        let credentials_source = self.resolve_credentials()?;
+        info!("Using the credentials from the {credentials_source}");
+        let settings = self.setting.clone().unwrap_or_else(DatalakeSetting::prod);
+        let client = match self.http_client.clone() {
+            Some(client) => client,
+            None => self.build_client(&settings)?,
+        };
+        Ok(AsyncDatalake {
+            settings,
+            username: self.username,
+            password: self.password,
+            longterm_token: self.longterm_token,
+            client,
+            tokens: Arc::new(RwLock::new(None)),
+            clock: self.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+            token_store: self.token_store,
+            credentials_source,
+        })
+    }
+
+    /// Credentials given to the builder first, then those of the first provider having some
+    fn resolve_credentials(&mut self) -> Result<String, DatalakeError> {
+        let has_credentials = self.username.is_some() && self.password.is_some();
+        if has_credentials || self.longterm_token.is_some() {
+            return Ok(BUILDER_CREDENTIALS_SOURCE.to_string());
+        }
+        for provider in &self.credential_providers {
+            match provider.credentials()? {
+                Some(Credentials::UsernamePassword { username, password }) => {
+                    self.username = Some(username);
+                    self.password = Some(password);
+                }
+                Some(Credentials::LongtermToken(longterm_token)) => self.longterm_token = Some(longterm_token),
+                None => {
+                    debug!("No credentials from the {}", provider.name());
+                    continue;
+                }
+            }
+            return Ok(provider.name());
+        }
+        let mut summary = "Either username & password must be provided together or longterm_token must be present.".to_string();
+        if !self.credential_providers.is_empty() {
+            let names: Vec<String> = self.credential_providers.iter().map(|provider| provider.name()).collect();
+            summary.push_str(&format!(" No credentials from the {}", names.join(", then the ")));
+        }
+        Err(AuthenticationError(DetailedError::new(summary)))
+    }
+
+    fn build_client(&self, settings: &DatalakeSetting) -> Result<Client, DatalakeError> {
+        let mut client_builder = proxy_client_builder(settings)?
+            .timeout(self.timeout.unwrap_or(Duration::from_secs(DEFAULT_HTTP_TIMEOUT)))
+            .user_agent(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+        for certificate in &self.root_certificates {
+            client_builder = client_builder.add_root_certificate(certificate.clone());
+        }
+        // Proxy settings are checked by build_proxy, build only fails on the TLS setup
+        client_builder
+            .build()
+            .map_err(|e| ClientError(DetailedError::new(format!("Failed to build client : {e}"))))
+    }
+}
+
+/// Client builder going through the proxy from the settings or from the HTTP(S)_PROXY env variables
+fn proxy_client_builder(settings: &DatalakeSetting) -> Result<ClientBuilder, DatalakeError> {
+    let client_builder = Client::builder();
+    let proxy_setting = match &settings.proxy {
+        Some(proxy_setting) => Some(proxy_setting.clone()),
+        // Try HTTPS_PROXY first, then HTTP_PROXY, otherwise use default client
+        None => env::var("HTTPS_PROXY").or_else(|_| env::var("HTTP_PROXY")).ok().map(|url| ProxySetting {
+            no_proxy: env::var("NO_PROXY").ok(),
+            ..ProxySetting::new(url)
+        }),
+    };
+    match proxy_setting {
+        Some(proxy_setting) => Ok(client_builder.proxy(build_proxy(&proxy_setting)?)),
+        None => {
+            debug!("No proxies configured, using default client. To specify a proxy, please set the HTTP_PROXY(S) env variable.");
+            Ok(client_builder)
+        }
+    }
+}
+
+fn build_proxy(proxy_setting: &ProxySetting) -> Result<Proxy, DatalakeError> {
+    let url = &proxy_setting.url;
+    debug!("Using Proxy : {url}");
+    if !PROXY_SCHEMES.iter().any(|scheme| url.starts_with(scheme)) {
+        return Err(ProxyError(DetailedError::new(format!("Invalid proxy URL : {url}"))));
+    }
+    let mut proxy = Proxy::all(url)
+        .map_err(|e| ProxyError(DetailedError::new(format!("Invalid proxy URL : {e}"))))?;
+    match (&proxy_setting.username, &proxy_setting.password) {
+        (Some(username), Some(password)) => proxy = proxy.basic_auth(username, password.expose()),
+        (None, None) => {}
+        _ => return Err(ProxyError(DetailedError::new("Proxy username and password must be provided together".to_string()))),
+    }
+    if let Some(no_proxy) = &proxy_setting.no_proxy {
+        proxy = proxy.no_proxy(NoProxy::from_string(no_proxy));
+    }
+    Ok(proxy)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::Arc;
+    use rstest::rstest;
+    use crate::{Datalake, DatalakeSetting, ProxySetting};
+    use crate::credentials::{EnvCredentialProvider, FileCredentialProvider};
+    use crate::error::DatalakeError::{AuthenticationError, ClientError, ProxyError};
+    use crate::tests::ENV_MUTEX;
+
+    fn build_with_setting(setting: DatalakeSetting) -> Result<Datalake, crate::DatalakeError> {
+        Datalake::builder()
+            .longterm_token("longterm_token".to_string())
+            .setting(setting)
+            .build()
+    }
+
+    #[test]
+    fn test_proxy_client_creation() {
+        let _mutex = ENV_MUTEX.lock().unwrap();
+        let setting = DatalakeSetting::prod();
+        env::remove_var("HTTP_PROXY");
+        env::remove_var("HTTPS_PROXY");
+        let result = build_with_setting(setting.clone());
+        assert!(result.is_ok(), "Should build client with no proxy");
+
+        env::set_var("HTTPS_PROXY", "https://example-proxy-1.com:8080");
+        let result = build_with_setting(setting.clone());
+        assert!(result.is_ok(), "Should build client with valid HTTP proxy");
+
+        env::remove_var("HTTPS_PROXY");
+        env::set_var("HTTP_PROXY", "http://example-proxy-2.com:8080");
+        let result = build_with_setting(setting.clone());
+        assert!(result.is_ok(), "Should build client with valid HTTP proxy");
+
+        env::set_var("HTTP_PROXY", "invalid-url");
+        let result = build_with_setting(setting);
+        assert!(result.is_err(), "Should fail with invalid proxy URL");
+
+        env::remove_var("HTTP_PROXY");
+        env::remove_var("HTTPS_PROXY");
+    }
+
+    #[rstest]
+    #[case(ProxySetting::new("http://proxy.local:3128".to_string()))]
+    #[case(ProxySetting::new("socks5://proxy.local:1080".to_string()))]
+    #[case(ProxySetting {
+        username: Some("user".to_string()),
+        password: Some("pass".into()),
+        no_proxy: Some("localhost,.internal.corp".to_string()),
+        ..ProxySetting::new("https://proxy.local:3128".to_string())
+    })]
+    fn test_proxy_setting_client_creation(#[case] proxy_setting: ProxySetting) {
+        let mut setting = DatalakeSetting::prod();
+        setting.proxy = Some(proxy_setting);
+        assert!(build_with_setting(setting).is_ok());
+    }
+
+    #[rstest]
+    #[case(ProxySetting::new("proxy.local:3128".to_string()), "Proxy Error Invalid proxy URL : proxy.local:3128")]
+    #[case(ProxySetting {
+        username: Some("user".to_string()),
+        ..ProxySetting::new("http://proxy.local:3128".to_string())
+    }, "Proxy Error Proxy username and password must be provided together")]
+    fn test_invalid_proxy_setting(#[case] proxy_setting: ProxySetting, #[case] expected_error: &str) {
+        let mut setting = DatalakeSetting::prod();
+        setting.proxy = Some(proxy_setting);
+        let err = build_with_setting(setting.clone()).err().unwrap();
+        assert!(matches!(err, ProxyError(_)));
+        assert_eq!(err.to_string(), expected_error);
+        let dtl_err = Datalake::new(None, None, Some("longterm_token".to_string()), setting).err().unwrap();
+        assert_eq!(dtl_err, expected_error);
+    }
+
+    #[test]
+    fn test_blocking_clients_share_a_runtime() {
+        let _mutex = ENV_MUTEX.lock().unwrap();
+        let dtl = build_with_setting(DatalakeSetting::prod()).unwrap();
+        let other_dtl = build_with_setting(DatalakeSetting::preprod()).unwrap();
+
+        assert!(std::ptr::eq(dtl.runtime, other_dtl.runtime));
+    }
+
+    #[tokio::test]
+    async fn test_drop_blocking_client_in_async_context() {
+        let dtl = {
+            let _mutex = ENV_MUTEX.lock().unwrap();
+            build_with_setting(DatalakeSetting::prod()).unwrap()
+        };
+
+        drop(dtl);  // Would panic if the client owned its runtime
+    }
+
+    #[test]
+    fn test_build_with_invalid_root_certificate() {
+        let _mutex = ENV_MUTEX.lock().unwrap();
+        let certificate = reqwest::Certificate::from_der(b"not a certificate").unwrap();
+
+        let err = Datalake::builder()
+            .longterm_token("longterm_token".to_string())
+            .root_certificate(certificate)
+            .build_async()
+            .err()
+            .unwrap();
+
+        assert!(matches!(err, ClientError(_)), "Unexpected error {err:?}");
+    }
+
+    #[test]
+    fn test_build_without_credentials() {
+        let _mutex = ENV_MUTEX.lock().unwrap();  // Building the client reads the proxy env variables
+        let err = Datalake::builder().setting(DatalakeSetting::prod()).build().err().unwrap();
+        assert!(matches!(err, AuthenticationError(_)));
+        let err = Datalake::builder().credentials("username".to_string(), "".to_string()).build_async().err();
+        assert!(err.is_none(), "username & password are enough");
+    }
+
+    #[test]
+    fn test_build_with_credential_providers() {
+        let _mutex = ENV_MUTEX.lock().unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let file_provider = Arc::new(FileCredentialProvider::new(directory.path()));
+        let env_provider = Arc::new(EnvCredentialProvider::with_prefix("OCD_DTL_RS_TEST_BUILDER_"));
+        env::set_var("OCD_DTL_RS_TEST_BUILDER_LONGTERM_TOKEN", "longterm_token");
+
+        let dtl = Datalake::builder()
+            .credential_provider(file_provider.clone())
+            .credential_provider(env_provider.clone())
+            .build_async()
+            .unwrap();
+        assert_eq!(dtl.credentials_source(), "environment variables OCD_DTL_RS_TEST_BUILDER_*");
+        assert_eq!(dtl.longterm_token.unwrap().expose(), "longterm_token");
+
+        let dtl = Datalake::builder()
+            .credentials("username".to_string(), "password".to_string())
+            .credential_provider(env_provider.clone())
+            .build_async()
+            .unwrap();
+        assert_eq!(dtl.credentials_source(), "builder");
+
+        env::remove_var("OCD_DTL_RS_TEST_BUILDER_LONGTERM_TOKEN");
+        let err = Datalake::builder()
+            .credential_provider(file_provider)
+            .credential_provider(env_provider)
+            .build_async()
+            .unwrap_err();
+        let expected_error = format!(
+            "Authentication Error Either username & password must be provided together or longterm_token must be present. \
+            No credentials from the files in {}, then the environment variables OCD_DTL_RS_TEST_BUILDER_*",
+            directory.path().display(),
+        );
+        assert_eq!(err.to_string(), expected_error);
+    }
+
+    #[test]
+    fn test_builder_debug_redacts_credentials() {
+        let builder = Datalake::builder().credentials("my_username".to_string(), "my_password".to_string());
+
+        let debug = format!("{builder:?}");
+
+        assert!(!debug.contains("my_username") && !debug.contains("my_password"), "{debug}");
+        assert!(debug.contains(r#"username: Some("[REDACTED]")"#), "{debug}");
+    }
+
+    #[test]
+    fn test_build_defaults_to_prod_setting() {
+        let _mutex = ENV_MUTEX.lock().unwrap();
+        let dtl = Datalake::builder().longterm_token("longterm_token".to_string()).build().unwrap();
+        assert_eq!(dtl.inner.settings.base_url(), DatalakeSetting::prod().base_url());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
+use csv::StringRecord;
+use serde::{Deserialize, Serialize};
+use crate::{ApiError, AsyncDatalake, AuthenticationError, Datalake, DatalakeError, DetailedError};
+use crate::atom::{AtomType, HashType};
+use crate::threat::ThreatType;
+
+pub(crate) const SCORE_COLUMN_SUFFIX: &str = ".score.risk";  // Score columns are named like malware.score.risk
+
+/// Result of the bulk lookup of a single atom value
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LookupResult {
+    pub atom_value: String,
+    pub atom_type: Option<AtomType>,  // None if the atom is not known by the Datalake
+    pub found: bool,
+    pub hashkey: String,
+    pub scores: BTreeMap<ThreatType, u8>,
+    pub tags: Vec<String>,
+    pub sources: Vec<String>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_updated: Option<DateTime<Utc>>,
+}
+
+/// Failure of a single chunk of a bulk lookup
+#[derive(Debug, PartialEq)]
+pub struct ChunkError {
+    pub chunk_index: usize,
+    pub atom_values: Vec<String>,
+    pub error: DatalakeError,
+}
+
+/// Outcome of a bulk lookup going on after failed chunks, see [Datalake::bulk_lookup_partial]
+#[derive(Debug, PartialEq)]
+pub struct BulkLookupReport {
+    pub csv: String,  // Merged CSV of the chunks looked up successfully
+    pub chunk_errors: Vec<ChunkError>,
+    /// Atom values of the failed chunks and of the ones skipped after an authentication error, in input order
+    pub unprocessed_atom_values: Vec<String>,
+}
+
+impl BulkLookupReport {
+    /// Whether every atom value was looked up
+    pub fn is_complete(&self) -> bool {
+        self.unprocessed_atom_values.is_empty()
+    }
+}
+
+impl LookupResult {
+    /// Parse the CSV returned by the bulk lookup API
+    pub fn from_csv(csv: &str) -> Result<Vec<LookupResult>, DatalakeError> {
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let headers = reader.headers().map_err(|err| Self::csv_error(csv, err))?.clone();
+        let mut results = Vec::new();
+        for record in reader.records() {
+            let record = record.map_err(|err| Self::csv_error(csv, err))?;
+            results.push(Self::from_record(&headers, &record));
+        }
+        Ok(results)
+    }
+
+    fn from_record(headers: &StringRecord, record: &StringRecord) -> LookupResult {
+        let column = |name: &str| -> Option<String> {
+            let index = headers.iter().position(|header| header == name)?;
+            record.get(index).filter(|value| !value.is_empty()).map(str::to_string)
+        };
+        let date = |name: &str| -> Option<DateTime<Utc>> {
+            column(name).and_then(|date| DateTime::parse_from_rfc3339(&date).ok()).map(|date| date.to_utc())
+        };
+        let mut scores = BTreeMap::new();
+        for (header, value) in headers.iter().zip(record.iter()) {
+            let threat_type = header.strip_suffix(SCORE_COLUMN_SUFFIX).and_then(|name| ThreatType::from_str(name).ok());
+            if let (Some(threat_type), Ok(score)) = (threat_type, value.parse::<u8>()) {
+                scores.insert(threat_type, score);
+            }
+        }
+        LookupResult {
+            atom_value: column("atom_value").or_else(|| column("search_phrase")).unwrap_or_default(),
+            atom_type: column("atom_type").and_then(|atom_type| AtomType::from_str(&atom_type).ok()),
+            found: column("threat_found").is_some_and(|found| found.eq_ignore_ascii_case("true")),
+            hashkey: column("hashkey").unwrap_or_default(),
+            scores,
+            tags: Self::split_list(column("tags")),
+            sources: Self::split_list(column("sources")),
+            first_seen: date("first_seen"),
+            last_updated: date("last_updated"),
+        }
+    }
+
+    fn split_list(value: Option<String>) -> Vec<String> {
+        split_list_column(&value.unwrap_or_default())
+    }
+
+    pub(crate) fn csv_error(csv: &str, err: csv::Error) -> DatalakeError {
+        let detailed_error = DetailedError {
+            summary: format!("unexpected csv result, {err}"),
+            api_url: None,
+            api_response: Some(csv.to_string()),
+            api_status_code: None,
+        };
+        ApiError(detailed_error)
+    }
+}
+
+/// Items of a comma separated CSV cell, like the tags or sources of a threat
+pub(crate) fn split_list_column(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Datalake {
+    /// Bulk lookup given threats, returning one typed result per atom value
+    ///
+    /// See [Datalake::bulk_lookup] for the treat_hashes_like parameter.
+    pub fn bulk_lookup_typed(&self, atom_values: Vec<String>, treat_hashes_like: HashType) -> Result<Vec<LookupResult>, DatalakeError> {
+        self.runtime.block_on(self.inner.bulk_lookup_typed(atom_values, treat_hashes_like))
+    }
+
+    /// Bulk lookup given threats, going on after a chunk fails
+    ///
+    /// Only an authentication error stops the lookup. The report lists the atom values to retry.
+    pub fn bulk_lookup_partial(&self, atom_values: Vec<String>, treat_hashes_like: HashType) -> BulkLookupReport {
+        self.runtime.block_on(self.inner.bulk_lookup_partial(atom_values, treat_hashes_like))
+    }
+}
+
+impl AsyncDatalake {
+    /// Bulk lookup given threats, returning one typed result per atom value
+    ///
+    /// See [AsyncDatalake::bulk_lookup] for the treat_hashes_like parameter.
+    pub async fn bulk_lookup_typed(&self, atom_values: Vec<String>, treat_hashes_like: HashType) -> Result<Vec<LookupResult>, DatalakeError> {
+        let mut results = Vec::with_capacity(atom_values.len());
+        for csv in self.bulk_lookup_chunks(&atom_values, &treat_hashes_like).await? {
+            results.extend(LookupResult::from_csv(&csv)?);
+        }
+        Ok(results)
+    }
+
+    /// Bulk lookup given threats, going on after a chunk fails
+    ///
+    /// Only an authentication error stops the lookup. The report lists the atom values to retry.
+    pub async fn bulk_lookup_partial(&self, atom_values: Vec<String>, treat_hashes_like: HashType) -> BulkLookupReport {
+        let chunks: Vec<&[String]> = atom_values.chunks(self.settings.bulk_lookup_chunk_size).collect();
+        let csv_by_chunk = self.bulk_lookup_chunks_until(&chunks, &treat_hashes_like, |err| matches!(err, AuthenticationError(_))).await;
+        let mut report = BulkLookupReport { csv: String::new(), chunk_errors: Vec::new(), unprocessed_atom_values: Vec::new() };
+        for (chunk_index, (chunk, csv)) in chunks.into_iter().zip(csv_by_chunk).enumerate() {
+            let appended = match csv {
+                Some(csv) => csv.and_then(|csv| Self::append_csv(&mut report.csv, csv)),
+                None => {  // Skipped after an authentication error
+                    report.unprocessed_atom_values.extend_from_slice(chunk);
+                    continue;
+                }
+            };
+            if let Err(error) = appended {
+                report.unprocessed_atom_values.extend_from_slice(chunk);
+                report.chunk_errors.push(ChunkError { chunk_index, atom_values: chunk.to_vec(), error });
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use crate::atom::AtomType;
+    use crate::bulk_lookup::LookupResult;
+    use crate::threat::ThreatType;
+
+    #[test]
+    fn test_lookup_result_from_csv() {
+        let csv = "hashkey,atom_type,search_phrase,atom_value,threat_found,first_seen,last_updated,malware.score.risk,phishing.score.risk,sources,tags\n\
+        000001a049b612930338a3ff293967d6,file,abc,abc,True,2021-11-16T03:39:56Z,2022-08-05T08:37:26Z,5,,\"src_1, src_2\",\"ransomware,apt\"\n\
+        736e1acf892a27598d65a52136122699,,ef3363dfe2515b826584ab53c4bb7812,,False,,,,,,\n";
+
+        let results = LookupResult::from_csv(csv).unwrap();
+
+        assert_eq!(results.len(), 2);
+        let found = &results[0];
+        assert!(found.found);
+        assert_eq!(found.atom_type, Some(AtomType::File));
+        assert_eq!(found.scores.get(&ThreatType::Malware), Some(&5));
+        assert_eq!(found.scores.get(&ThreatType::Phishing), None);
+        assert_eq!(found.sources, ["src_1", "src_2"]);
+        assert_eq!(found.tags, ["ransomware", "apt"]);
+        assert_eq!(found.first_seen, Some(Utc.with_ymd_and_hms(2021, 11, 16, 3, 39, 56).unwrap()));
+        assert_eq!(found.last_updated, Some(Utc.with_ymd_and_hms(2022, 8, 5, 8, 37, 26).unwrap()));
+        let not_found = &results[1];
+        assert!(!not_found.found);
+        assert_eq!(not_found.atom_value, "ef3363dfe2515b826584ab53c4bb7812");  // Falls back on the search phrase
+        assert_eq!(not_found.atom_type, None);
+        assert!(not_found.scores.is_empty());
+        assert_eq!(not_found.first_seen, None);
+    }
+
+    #[test]
+    fn test_lookup_result_from_invalid_csv() {
+        let csv = "hashkey,atom_type\n123,file,unexpected_column\n";
+
+        let err = LookupResult::from_csv(csv).unwrap_err();
+
+        assert!(err.to_string().starts_with("API Error unexpected csv result"));
+    }
+}
@@ -1,15 +1,34 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
-use crate::{ApiError, Datalake, DatalakeError, DetailedError};
-use strum_macros::{EnumString, Display};
+use serde_json::{json, Map, Value};
+use crate::atom::{impl_api_name, AtomType};
+use crate::bulk_lookup::{split_list_column, LookupResult, SCORE_COLUMN_SUFFIX};
+use crate::threat::ThreatType;
+use crate::polling::with_jitter;
+use crate::{ApiError, AsyncDatalake, Datalake, DatalakeError, DetailedError, TimeoutError};
+use crate::DatalakeError::{HttpError, IoError, ParseError};
+use log::warn;
+use reqwest::{Response, StatusCode};
+use reqwest::header::RANGE;
+use strum_macros::{EnumString, Display, IntoStaticStr};
+use std::fmt;
+use std::io::{SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Runtime;
 
 const BULK_SEARCH_DOWNLOAD_TIMEOUT: u64 = 3600;
-type TaskUuid = String;
+const PARTIAL_DOWNLOAD_SUFFIX: &str = ".part";  // Downloads are written next to their final path until complete
+const DOWNLOAD_MAX_RETRIES: u32 = 5;
+const BULK_SEARCH_TASKS_PAGE_SIZE: usize = 100;
+pub type TaskUuid = String;
 
+/// State of a bulk search task, states not known by this library are kept as `Unknown`
 #[allow(non_camel_case_types)]
-#[derive(Debug, Display, PartialEq, Eq, EnumString)]
+#[derive(Debug, IntoStaticStr, Clone, PartialEq, Eq, EnumString)]
 pub enum State {
     NEW,
     QUEUED,
@@ -18,136 +37,898 @@ pub enum State {
     CANCELLED,
     FAILED_ERROR,
     FAILED_TIMEOUT,
+    #[strum(default)]
+    Unknown(String),
 }
 
+impl_api_name!(State);
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+
+/// Query a bulk search task was created with
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct BulkSearchQuery {
+    #[serde(rename = "advanced_query_hash", alias = "query_hash")]
+    pub query_hash: String,
+    #[serde(default)]
+    pub query_fields: Vec<String>,
+    #[serde(default)]
+    pub for_stix_export: bool,
+}
+
+/// Organization a Datalake user belongs to
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Organization {
+    pub id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub path_names: Vec<String>,  // Names from the root organization down to this one
+}
+
+/// User who created a bulk search task
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct User {
+    pub id: u64,
+    pub email: String,
+    pub full_name: Option<String>,
+    pub organization: Option<Organization>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct BulkSearchTask {
-    pub created_at: String,
-    pub started_at: Option<String>,
-    pub finished_at: Option<String>,
+    pub uuid: TaskUuid,
+    pub state: State,
+    pub bulk_search_hash: Option<String>,
+    pub bulk_search: Option<BulkSearchQuery>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub eta: Option<DateTime<Utc>>,  // Estimated end of the processing
+    pub progress: Option<f64>,  // Percentage of the processing done
     pub queue_position: Option<i64>,
     pub results: Option<i64>,
-    pub state: String,
-    pub uuid: TaskUuid,
+    pub file_size: Option<u64>,  // Size in bytes of the result file, once the task is done
+    #[serde(default)]
+    pub file_deleted: bool,
+    pub file_delete_after: Option<DateTime<Utc>>,  // Result file is not downloadable after this date
+    pub user: Option<User>,
 }
 
 impl BulkSearchTask {
+    /// Kept for backward compatibility, the state is now directly available in the `state` field
     pub fn get_state(&self) -> Result<State, DatalakeError> {
-        match State::from_str(&self.state) {
-            Ok(state) => Ok(state),
-            Err(_) => {
-                let error_summary = format!("Bulk search is in unexpected state: {}", self.state);
-                Err(ApiError(DetailedError::new(error_summary)))
-            }
+        Ok(self.state.clone())
+    }
+}
+
+/// Criteria of [list_bulk_search_tasks], a task must match all the criteria set
+///
+/// ```
+/// use ocd_datalake_rs::bulk_search::{BulkSearchTaskFilter, State};
+///
+/// let done_tasks_of_query = BulkSearchTaskFilter {
+///     states: vec![State::DONE],
+///     query_hash: Some("fbecd3d440a7d439a2a1fd996c703a8d".to_string()),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BulkSearchTaskFilter {
+    pub states: Vec<State>,  // Task is in any of these states, no filter on the state if empty
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub query_hash: Option<String>,
+    pub query_fields: Option<Vec<String>>,  // Task was created with these query fields, in any order
+    pub file_available_at: Option<DateTime<Utc>>,  // Result file is not deleted and still downloadable at this date
+}
+
+impl BulkSearchTaskFilter {
+    pub fn matches(&self, task: &BulkSearchTask) -> bool {
+        let query_hash = task.bulk_search.as_ref().map(|bulk_search| &bulk_search.query_hash);
+        (self.states.is_empty() || self.states.contains(&task.state))
+            && self.created_after.is_none_or(|created_after| task.created_at >= created_after)
+            && self.created_before.is_none_or(|created_before| task.created_at < created_before)
+            && self.query_hash.as_ref().is_none_or(|expected_hash| query_hash == Some(expected_hash))
+            && self.query_fields.as_ref().is_none_or(|expected_fields| Self::same_fields(task, expected_fields))
+            && self.file_available_at.is_none_or(|date| Self::file_available_at(task, date))
+    }
+
+    fn same_fields(task: &BulkSearchTask, expected_fields: &[String]) -> bool {
+        let Some(bulk_search) = &task.bulk_search else {
+            return false;
+        };
+        let mut fields: Vec<&String> = bulk_search.query_fields.iter().collect();
+        let mut expected_fields: Vec<&String> = expected_fields.iter().collect();
+        fields.sort();
+        expected_fields.sort();
+        fields == expected_fields
+    }
+
+    fn file_available_at(task: &BulkSearchTask, date: DateTime<Utc>) -> bool {
+        !task.file_deleted && task.file_delete_after.is_none_or(|delete_after| delete_after > date)
+    }
+}
+
+#[derive(Deserialize)]
+struct BulkSearchTaskPage {
+    count: usize,
+    results: Vec<BulkSearchTask>,
+}
+
+/// Format of a bulk search result, chosen when the task is created and when it is downloaded
+#[derive(Serialize, Deserialize, Debug, Display, EnumString, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+    Stix,  // STIX 2.1 bundle
+}
+
+impl ExportFormat {
+    /// Accept header selecting the format in the API
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Json => "application/json",
+            ExportFormat::Stix => "application/stix+json",
         }
     }
 }
 
-/// Create a bulk search task and return its task_uuid
-pub fn create_bulk_search_task(dtl: &mut Datalake, query_hash: String, query_fields: Vec<String>) -> Result<TaskUuid, DatalakeError> {
-    let url = dtl.settings.routes().bulk_search.clone();
+/// Row of a bulk search exported as JSON, only the query fields requested are set
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BulkSearchRecord {
+    pub atom_value: Option<String>,
+    pub atom_type: Option<AtomType>,
+    pub threat_hashkey: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,  // Other query fields, like scores or tags
+}
 
-    let mut body = Map::new();
-    body.insert("query_hash".to_string(), Value::String(query_hash));
-    let query_fields_serialized = Value::Array(query_fields.into_iter().map(Value::String).collect());
-    body.insert("query_fields".to_string(), query_fields_serialized);
+/// Field of the threats returned by a bulk search, checked before the task is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueryField {
+    AtomValue,
+    AtomType,
+    ThreatHashkey,
+    ThreatTypes,
+    /// Risk score for a threat type, like `malware.score.risk`
+    Score(ThreatType),
+    Tags,
+    Sources,
+    FirstSeen,
+    LastUpdated,
+    EventsNumber,
+    Md5,
+    Sha1,
+    Sha256,
+}
 
-    let request = dtl.client.post(&url)
-        .header("Accept", "text/csv")
-        .json(&body);
-    let resp = dtl.run_with_authorization_token(&request)?;
-    let status_code = resp.status();
-    let json_response = resp.json::<Value>()?;
+impl QueryField {
+    const NAMED_FIELDS: [(QueryField, &'static str); 12] = [
+        (QueryField::AtomValue, "atom_value"),
+        (QueryField::AtomType, "atom_type"),
+        (QueryField::ThreatHashkey, "threat_hashkey"),
+        (QueryField::ThreatTypes, "threat_types"),
+        (QueryField::Tags, "tags"),
+        (QueryField::Sources, "sources"),
+        (QueryField::FirstSeen, "first_seen"),
+        (QueryField::LastUpdated, "last_updated"),
+        (QueryField::EventsNumber, "events_number"),
+        (QueryField::Md5, ".hashes.md5"),
+        (QueryField::Sha1, ".hashes.sha1"),
+        (QueryField::Sha256, ".hashes.sha256"),
+    ];
 
-    fn parse_json_response(json_resp: &Value) -> Option<String> {
-        Some(json_resp.as_object()?.get("task_uuid")?.as_str()?.to_string())
+    /// Parse the query fields of a bulk search, failing on the first unknown one
+    pub fn parse_all(names: &[String]) -> Result<Vec<QueryField>, DatalakeError> {
+        if names.is_empty() {
+            return Err(ParseError(DetailedError::new("at least one bulk search query field is required".to_string())));
+        }
+        names.iter().map(|name| QueryField::from_str(name)).collect()
     }
 
-    match parse_json_response(&json_response) {
-        Some(task) => { Ok(task) }
-        None => {
-            let err = DetailedError {
-                summary: "bulk search API response not as expected".to_string(),
-                api_url: Some(url),
-                api_response: Some(json_response.to_string()),
-                api_status_code: Some(status_code),
-            };
-            Err(ApiError(err))
+    /// Whether a CSV column holds this field, the leading dot of hashes being optional
+    fn is_column(&self, header: &str) -> bool {
+        header.trim_start_matches('.') == self.to_string().trim_start_matches('.')
+    }
+}
+
+impl fmt::Display for QueryField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let QueryField::Score(threat_type) = self {
+            return write!(f, "{threat_type}{SCORE_COLUMN_SUFFIX}");
         }
+        let (_, name) = QueryField::NAMED_FIELDS.iter()
+            .find(|(field, _)| field == self)
+            .expect("every field but scores is named");
+        write!(f, "{name}")
     }
 }
 
-/// Retrieve a bulk search task from a uuid
-pub fn get_bulk_search_task(dtl: &mut Datalake, uuid: TaskUuid) -> Result<BulkSearchTask, DatalakeError> {
-    let url = dtl.settings.routes().bulk_search_task.clone();
-    let mut body = Map::new();
-    body.insert("task_uuid".to_string(), Value::String(uuid));
+impl FromStr for QueryField {
+    type Err = DatalakeError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if let Some((field, _)) = QueryField::NAMED_FIELDS.iter().find(|(_, field_name)| *field_name == name) {
+            return Ok(*field);
+        }
+        name.strip_suffix(SCORE_COLUMN_SUFFIX)
+            .and_then(|threat_type| ThreatType::from_str(threat_type).ok())
+            .map(QueryField::Score)
+            .ok_or_else(|| ParseError(DetailedError::new(format!("unknown bulk search query field: {name}"))))
+    }
+}
+
+impl From<QueryField> for String {
+    fn from(field: QueryField) -> Self {
+        field.to_string()
+    }
+}
 
-    let request = dtl.client.post(&url)
-        .header("Accept", "application/json")
-        .json(&body);
-    let resp = dtl.run_with_authorization_token(&request)?;
+impl BulkSearchRecord {
+    /// Parse a CSV bulk search result, reading the column of each query field of the task
+    ///
+    /// Every column named after a query field is read if `query_fields` is empty.
+    pub fn from_csv(csv: &str, query_fields: &[QueryField]) -> Result<Vec<BulkSearchRecord>, DatalakeError> {
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let headers = reader.headers().map_err(|err| LookupResult::csv_error(csv, err))?.clone();
+        let columns: Vec<(QueryField, usize)> = if query_fields.is_empty() {
+            headers.iter().enumerate()
+                .filter_map(|(index, header)| Some((QueryField::from_str(header).ok()?, index)))
+                .collect()
+        } else {
+            query_fields.iter().map(|field| {
+                match headers.iter().position(|header| field.is_column(header)) {
+                    Some(index) => Ok((*field, index)),
+                    None => {
+                        let mut err = DetailedError::new(format!("bulk search CSV has no {field} column"));
+                        err.api_response = Some(csv.to_string());
+                        Err(ApiError(err))
+                    }
+                }
+            }).collect::<Result<_, _>>()?
+        };
+        let mut records = Vec::new();
+        for row in reader.records() {
+            let row = row.map_err(|err| LookupResult::csv_error(csv, err))?;
+            let mut record = BulkSearchRecord { atom_value: None, atom_type: None, threat_hashkey: None, extra: Map::new() };
+            for (field, index) in &columns {
+                let Some(value) = row.get(*index).filter(|value| !value.is_empty()) else { continue };
+                match field {
+                    QueryField::AtomValue => record.atom_value = Some(value.to_string()),
+                    QueryField::AtomType => record.atom_type = AtomType::from_str(value).ok(),
+                    QueryField::ThreatHashkey => record.threat_hashkey = Some(value.to_string()),
+                    QueryField::ThreatTypes | QueryField::Tags | QueryField::Sources => {
+                        let items = split_list_column(value).into_iter().map(Value::String).collect();
+                        record.extra.insert(field.to_string(), Value::Array(items));
+                    }
+                    QueryField::Score(_) | QueryField::EventsNumber => {
+                        let number = value.parse::<u64>().map(Value::from).unwrap_or_else(|_| Value::String(value.to_string()));
+                        record.extra.insert(field.to_string(), number);
+                    }
+                    _ => {
+                        record.extra.insert(field.to_string(), Value::String(value.to_string()));
+                    }
+                }
+            }
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
 
-    // Prepare fields for error message
-    let status_code = resp.status();
-    let json_response = resp.json::<Value>()?;
-    let api_response = Some(json_response.to_string());
+/// Handle on a bulk search task, to follow it without blocking for the whole processing
+///
+/// See [Datalake::start_bulk_search], or [BulkSearchHandle::from_uuid] to resume a task created earlier.
+#[derive(Clone, Debug)]
+pub struct BulkSearchHandle {
+    runtime: &'static Runtime,
+    inner: AsyncBulkSearchHandle,
+}
 
-    fn parse_json_response(json_resp: Value) -> Option<Value> {
-        json_resp.as_object()?.get("results")?.get(0).cloned()
+impl BulkSearchHandle {
+    /// Handle on an existing task, e.g. one created before a crash, so it is not submitted again
+    pub fn from_uuid(dtl: &Datalake, task_uuid: TaskUuid) -> Self {
+        BulkSearchHandle {
+            runtime: dtl.runtime,
+            inner: AsyncBulkSearchHandle::from_uuid(&dtl.inner, task_uuid),
+        }
     }
 
-    // Prepare an error if the received json is not a BulkSearchTask
-    let summary = "bulk search task API response not as expected".to_string();
-    let err = DetailedError { summary, api_url: Some(url), api_response, api_status_code: Some(status_code) };
-    let api_error = Err(ApiError(err));
+    /// Format the result is downloaded in, the one the task was created with if it was started from this handle
+    pub fn with_format(self, format: ExportFormat) -> Self {
+        BulkSearchHandle { inner: self.inner.with_format(format), ..self }
+    }
 
-    match parse_json_response(json_response) {
-        Some(task) => {
-            match serde_json::from_value::<BulkSearchTask>(task) {
-                Ok(task) => Ok(task),
-                Err(_) => api_error,
+    pub fn task_uuid(&self) -> &TaskUuid {
+        self.inner.task_uuid()
+    }
+
+    pub fn format(&self) -> ExportFormat {
+        self.inner.format()
+    }
+
+    /// Retrieve the task once, without waiting for it to be processed
+    pub fn poll(&self) -> Result<BulkSearchTask, DatalakeError> {
+        self.runtime.block_on(self.inner.poll())
+    }
+
+    /// Wait for the task to be processed then download its result, `callback` is called after each poll.
+    /// > **Warning** the function is blocking up to the bulk_search_timeout_sec setting
+    pub fn wait_with<F: FnMut(&BulkSearchTask)>(&self, callback: F) -> Result<String, DatalakeError> {
+        self.runtime.block_on(self.inner.wait_with(callback))
+    }
+
+    /// Same as [BulkSearchHandle::wait_with], returning the typed records of a CSV or JSON result
+    pub fn wait_records_with<F: FnMut(&BulkSearchTask)>(&self, callback: F) -> Result<Vec<BulkSearchRecord>, DatalakeError> {
+        self.runtime.block_on(self.inner.wait_records_with(callback))
+    }
+
+    /// Wait for the task to be processed then stream its result to a file, see [BulkSearchHandle::wait_with]
+    ///
+    /// Return the number of bytes written, no file is left at `path` if the download fails.
+    pub fn wait_to_file<F: FnMut(&BulkSearchTask)>(&self, path: &Path, callback: F) -> Result<u64, DatalakeError> {
+        self.runtime.block_on(self.inner.wait_to_file(path, callback))
+    }
+
+    /// Ask the API to stop processing the task
+    pub fn cancel(&self) -> Result<(), DatalakeError> {
+        self.runtime.block_on(self.inner.cancel())
+    }
+}
+
+/// Handle on a bulk search task, see [BulkSearchHandle]
+#[derive(Clone, Debug)]
+pub struct AsyncBulkSearchHandle {
+    dtl: AsyncDatalake,
+    task_uuid: TaskUuid,
+    format: ExportFormat,
+}
+
+impl AsyncBulkSearchHandle {
+    /// Handle on an existing task, e.g. one created before a crash, so it is not submitted again
+    pub fn from_uuid(dtl: &AsyncDatalake, task_uuid: TaskUuid) -> Self {
+        AsyncBulkSearchHandle { dtl: dtl.clone(), task_uuid, format: ExportFormat::default() }
+    }
+
+    /// Format the result is downloaded in, the one the task was created with if it was started from this handle
+    pub fn with_format(self, format: ExportFormat) -> Self {
+        AsyncBulkSearchHandle { format, ..self }
+    }
+
+    pub fn task_uuid(&self) -> &TaskUuid {
+        &self.task_uuid
+    }
+
+    pub fn format(&self) -> ExportFormat {
+        self.format
+    }
+
+    /// Retrieve the task once, without waiting for it to be processed
+    pub async fn poll(&self) -> Result<BulkSearchTask, DatalakeError> {
+        self.dtl.get_bulk_search_task(self.task_uuid.clone()).await
+    }
+
+    /// Wait for the task to be processed then download its result, `callback` is called after each poll
+    pub async fn wait_with<F: FnMut(&BulkSearchTask)>(&self, callback: F) -> Result<String, DatalakeError> {
+        self.wait_until_done(callback).await?;
+        self.dtl.download_bulk_search_as(self.task_uuid.clone(), self.format).await
+    }
+
+    /// Same as [AsyncBulkSearchHandle::wait_with], returning the typed records of a CSV or JSON result
+    ///
+    /// CSV columns are read for the query fields the task was created with.
+    pub async fn wait_records_with<F: FnMut(&BulkSearchTask)>(&self, callback: F) -> Result<Vec<BulkSearchRecord>, DatalakeError> {
+        let task = self.wait_until_done(callback).await?;
+        match self.format {
+            ExportFormat::Json => self.dtl.download_bulk_search_json(self.task_uuid.clone()).await,
+            ExportFormat::Csv => {
+                let query_fields = match task.bulk_search {
+                    Some(bulk_search) if !bulk_search.query_fields.is_empty() => QueryField::parse_all(&bulk_search.query_fields)?,
+                    _ => Vec::new(),  // Read every known column
+                };
+                let csv = self.dtl.download_bulk_search(self.task_uuid.clone()).await?;
+                BulkSearchRecord::from_csv(&csv, &query_fields)
             }
+            ExportFormat::Stix => {
+                Err(ParseError(DetailedError::new("STIX bulk search results are not read as records".to_string())))
+            }
+        }
+    }
+
+    /// Wait for the task to be processed then stream its result to a file, see [AsyncBulkSearchHandle::wait_with]
+    ///
+    /// Return the number of bytes written, no file is left at `path` if the download fails.
+    /// Interrupted downloads are resumed from the last byte received, and the file size is checked against the task.
+    pub async fn wait_to_file<F: FnMut(&BulkSearchTask)>(&self, path: &Path, callback: F) -> Result<u64, DatalakeError> {
+        let task = self.wait_until_done(callback).await?;
+        self.dtl.download_bulk_search_to_file(self.task_uuid.clone(), self.format, path, task.file_size).await
+    }
+
+    /// Poll the task until it is DONE and return its last state
+    ///
+    /// Polls are spaced by the bulk_search_polling strategy of the settings.
+    async fn wait_until_done<F: FnMut(&BulkSearchTask)>(&self, mut callback: F) -> Result<BulkSearchTask, DatalakeError> {
+        let settings = &self.dtl.settings;
+        let clock = &self.dtl.clock;
+        let timeout = settings.bulk_search_timeout_sec;
+        let interval = Duration::from_secs(settings.bulk_search_retry_interval_sec);
+        let start_time = clock.now();
+        let mut last_task: Option<BulkSearchTask> = None;
+        let mut polls: u32 = 0;
+        loop {
+            if (clock.now() - start_time).num_seconds() > timeout as i64 {
+                let error_summary = format!("Bulk search is not finished after {timeout} seconds");
+                return Err(TimeoutError(DetailedError::new(error_summary)));
+            }
+            let delay = settings.bulk_search_polling.delay(interval, polls, last_task.as_ref(), clock.now());
+            clock.sleep(with_jitter(delay, settings.bulk_search_polling_jitter)).await;
+            let task = self.poll().await?;
+            polls = polls.saturating_add(1);
+            callback(&task);
+            match &task.state {
+                State::DONE => return Ok(task),
+                State::NEW | State::QUEUED | State::IN_PROGRESS => {}  // bulk search is not ready yet
+                state @ (State::CANCELLED | State::FAILED_ERROR | State::FAILED_TIMEOUT) => {
+                    return Err(ApiError(DetailedError::new(format!("Bulk search ended with {state} state"))));
+                }
+                State::Unknown(state) => {
+                    return Err(ApiError(DetailedError::new(format!("Bulk search is in unexpected state: {state}"))));
+                }
+            }
+            last_task = Some(task);
         }
-        None => api_error,
     }
+
+    /// Ask the API to stop processing the task
+    pub async fn cancel(&self) -> Result<(), DatalakeError> {
+        self.dtl.cancel_bulk_search_task(self.task_uuid.clone()).await
+    }
+}
+
+impl Datalake {
+    /// Create a bulk search task and return a handle on it, without waiting for its processing
+    pub fn start_bulk_search(&self, query_hash: String, query_fields: Vec<String>) -> Result<BulkSearchHandle, DatalakeError> {
+        self.start_bulk_search_as(query_hash, query_fields, ExportFormat::Csv)
+    }
+
+    /// Same as [Datalake::start_bulk_search], exporting the result in the given format
+    pub fn start_bulk_search_as(&self, query_hash: String, query_fields: Vec<String>, format: ExportFormat) -> Result<BulkSearchHandle, DatalakeError> {
+        let task_uuid = create_bulk_search_task_as(self, query_hash, query_fields, format)?;
+        Ok(BulkSearchHandle::from_uuid(self, task_uuid).with_format(format))
+    }
+
+    /// Create a bulk search task, wait for it then stream its result to a file, see [Datalake::bulk_search]
+    ///
+    /// Return the number of bytes written, no file is left at `path` if the download fails.
+    pub fn bulk_search_to_file(&self, query_hash: String, query_fields: Vec<String>, path: &Path) -> Result<u64, DatalakeError> {
+        self.runtime.block_on(self.inner.bulk_search_to_file(query_hash, query_fields, path))
+    }
+}
+
+/// Create a bulk search task and return its task_uuid
+pub fn create_bulk_search_task(dtl: &Datalake, query_hash: String, query_fields: Vec<String>) -> Result<TaskUuid, DatalakeError> {
+    dtl.runtime.block_on(dtl.inner.create_bulk_search_task(query_hash, query_fields))
+}
+
+/// Create a bulk search task exporting its result in the given format and return its task_uuid
+pub fn create_bulk_search_task_as(dtl: &Datalake, query_hash: String, query_fields: Vec<String>, format: ExportFormat) -> Result<TaskUuid, DatalakeError> {
+    dtl.runtime.block_on(dtl.inner.create_bulk_search_task_as(query_hash, query_fields, format))
+}
+
+/// Retrieve a bulk search task from a uuid
+pub fn get_bulk_search_task(dtl: &Datalake, uuid: TaskUuid) -> Result<BulkSearchTask, DatalakeError> {
+    dtl.runtime.block_on(dtl.inner.get_bulk_search_task(uuid))
+}
+
+/// List every bulk search task of the account matching the filter
+pub fn list_bulk_search_tasks(dtl: &Datalake, filter: &BulkSearchTaskFilter) -> Result<Vec<BulkSearchTask>, DatalakeError> {
+    dtl.runtime.block_on(dtl.inner.list_bulk_search_tasks(filter))
+}
+
+/// Cancel a bulk search task that is not finished yet
+pub fn cancel_bulk_search_task(dtl: &Datalake, uuid: TaskUuid) -> Result<(), DatalakeError> {
+    dtl.runtime.block_on(dtl.inner.cancel_bulk_search_task(uuid))
 }
 
 /// Retrieve a bulk search result from a task.
 /// > **Warning** task must be in DONE state to be downloaded successfully
-pub fn download_bulk_search(dtl: &mut Datalake, uuid: TaskUuid) -> Result<String, DatalakeError> {
-    let url = dtl.settings.routes().bulk_search_download.replace("{task_uuid}", &uuid);
-    let request = dtl.client.get(&url)
-        .timeout(Duration::from_secs(BULK_SEARCH_DOWNLOAD_TIMEOUT))
-        .header("Accept", "text/csv");
-    let resp = dtl.run_with_authorization_token(&request)?;
-    let status_code = resp.status();
-
-    if status_code == 202 {
-        let err = DetailedError {
-            summary: format!("bulk search with task uuid: {uuid} is not ready to be downloaded"),
-            api_url: Some(url),
-            api_response: match resp.text() {
-                Ok(r) => Some(r),
-                Err(_) => None
+pub fn download_bulk_search(dtl: &Datalake, uuid: TaskUuid) -> Result<String, DatalakeError> {
+    dtl.runtime.block_on(dtl.inner.download_bulk_search(uuid))
+}
+
+/// Retrieve a bulk search result from a task in the given format, see [download_bulk_search]
+pub fn download_bulk_search_as(dtl: &Datalake, uuid: TaskUuid, format: ExportFormat) -> Result<String, DatalakeError> {
+    dtl.runtime.block_on(dtl.inner.download_bulk_search_as(uuid, format))
+}
+
+/// Retrieve the typed records of a bulk search task created with the JSON format, see [download_bulk_search]
+pub fn download_bulk_search_json(dtl: &Datalake, uuid: TaskUuid) -> Result<Vec<BulkSearchRecord>, DatalakeError> {
+    dtl.runtime.block_on(dtl.inner.download_bulk_search_json(uuid))
+}
+
+/// Stream a bulk search result from a task in the given format into `writer` and return the number of bytes written.
+/// > **Warning** task must be in DONE state to be downloaded successfully
+pub fn download_bulk_search_to<W: Write>(dtl: &Datalake, uuid: TaskUuid, format: ExportFormat, writer: &mut W) -> Result<u64, DatalakeError> {
+    // Chunks are written from the calling thread, the runtime only receives them
+    let mut resp = dtl.runtime.block_on(dtl.inner.bulk_search_download_response(&uuid, format, 0))?;
+    let mut bytes_written = 0;
+    while let Some(chunk) = dtl.runtime.block_on(resp.chunk())? {
+        writer.write_all(&chunk)?;
+        bytes_written += chunk.len() as u64;
+    }
+    writer.flush()?;
+    Ok(bytes_written)
+}
+
+impl AsyncDatalake {
+    /// Create a bulk search task and return a handle on it, without waiting for its processing
+    pub async fn start_bulk_search(&self, query_hash: String, query_fields: Vec<String>) -> Result<AsyncBulkSearchHandle, DatalakeError> {
+        self.start_bulk_search_as(query_hash, query_fields, ExportFormat::Csv).await
+    }
+
+    /// Same as [AsyncDatalake::start_bulk_search], exporting the result in the given format
+    pub async fn start_bulk_search_as(&self, query_hash: String, query_fields: Vec<String>, format: ExportFormat) -> Result<AsyncBulkSearchHandle, DatalakeError> {
+        let task_uuid = self.create_bulk_search_task_as(query_hash, query_fields, format).await?;
+        Ok(AsyncBulkSearchHandle::from_uuid(self, task_uuid).with_format(format))
+    }
+
+    /// Create a bulk search task and return its task_uuid
+    pub async fn create_bulk_search_task(&self, query_hash: String, query_fields: Vec<String>) -> Result<TaskUuid, DatalakeError> {
+        self.create_bulk_search_task_as(query_hash, query_fields, ExportFormat::Csv).await
+    }
+
+    /// Create a bulk search task exporting its result in the given format and return its task_uuid
+    pub async fn create_bulk_search_task_as(&self, query_hash: String, query_fields: Vec<String>, format: ExportFormat) -> Result<TaskUuid, DatalakeError> {
+        let url = self.settings.routes().bulk_search.clone();
+
+        let mut body = Map::new();
+        QueryField::parse_all(&query_fields)?;  // Fail before the task is created server side
+        body.insert("query_hash".to_string(), Value::String(query_hash));
+        let query_fields_serialized = Value::Array(query_fields.into_iter().map(Value::String).collect());
+        body.insert("query_fields".to_string(), query_fields_serialized);
+        if format == ExportFormat::Stix {
+            body.insert("for_stix_export".to_string(), Value::Bool(true));
+        }
+
+        let request = self.client.post(&url)
+            .header("Accept", format.mime_type())
+            .json(&body);
+        let resp = self.run_with_authorization_token(request).await?;
+        let status_code = resp.status();
+        let json_response = resp.json::<Value>().await?;
+
+        fn parse_json_response(json_resp: &Value) -> Option<String> {
+            Some(json_resp.as_object()?.get("task_uuid")?.as_str()?.to_string())
+        }
+
+        match parse_json_response(&json_response) {
+            Some(task) => { Ok(task) }
+            None => {
+                let err = DetailedError {
+                    summary: "bulk search API response not as expected".to_string(),
+                    api_url: Some(url),
+                    api_response: Some(json_response.to_string()),
+                    api_status_code: Some(status_code),
+                };
+                Err(ApiError(err))
+            }
+        }
+    }
+
+    /// Retrieve a bulk search task from a uuid
+    pub async fn get_bulk_search_task(&self, uuid: TaskUuid) -> Result<BulkSearchTask, DatalakeError> {
+        let url = self.settings.routes().bulk_search_task.clone();
+        let mut body = Map::new();
+        body.insert("task_uuid".to_string(), Value::String(uuid));
+
+        let request = self.client.post(&url)
+            .header("Accept", "application/json")
+            .json(&body);
+        let resp = self.run_with_authorization_token(request).await?;
+
+        // Prepare fields for error message
+        let status_code = resp.status();
+        let json_response = resp.json::<Value>().await?;
+        let api_response = Some(json_response.to_string());
+
+        fn parse_json_response(json_resp: Value) -> Option<Value> {
+            json_resp.as_object()?.get("results")?.get(0).cloned()
+        }
+
+        // Prepare an error if the received json is not a BulkSearchTask
+        let summary = "bulk search task API response not as expected".to_string();
+        let err = DetailedError { summary, api_url: Some(url), api_response, api_status_code: Some(status_code) };
+        let api_error = Err(ApiError(err));
+
+        match parse_json_response(json_response) {
+            Some(task) => match serde_json::from_value::<BulkSearchTask>(task) {
+                Ok(task) => Ok(task),
+                Err(_) => api_error,
             },
-            api_status_code: Some(status_code),
+            None => api_error,
+        }
+    }
+
+    /// Retrieve a bulk search result from a task.
+    /// > **Warning** task must be in DONE state to be downloaded successfully
+    pub async fn download_bulk_search(&self, uuid: TaskUuid) -> Result<String, DatalakeError> {
+        self.download_bulk_search_as(uuid, ExportFormat::Csv).await
+    }
+
+    /// Retrieve a bulk search result from a task in the given format, see [AsyncDatalake::download_bulk_search]
+    pub async fn download_bulk_search_as(&self, uuid: TaskUuid, format: ExportFormat) -> Result<String, DatalakeError> {
+        let resp = self.bulk_search_download_response(&uuid, format, 0).await?;
+        Ok(resp.text().await?)
+    }
+
+    /// Retrieve the typed records of a bulk search task created with the JSON format, see [AsyncDatalake::download_bulk_search]
+    pub async fn download_bulk_search_json(&self, uuid: TaskUuid) -> Result<Vec<BulkSearchRecord>, DatalakeError> {
+        let json_response = self.download_bulk_search_as(uuid, ExportFormat::Json).await?;
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum JsonResult {
+            Records(Vec<BulkSearchRecord>),
+            Page { results: Vec<BulkSearchRecord> },
+        }
+
+        match serde_json::from_str::<JsonResult>(&json_response) {
+            Ok(JsonResult::Records(records)) | Ok(JsonResult::Page { results: records }) => Ok(records),
+            Err(_) => {
+                let mut err = DetailedError::new("bulk search JSON result not as expected".to_string());
+                err.api_response = Some(json_response);
+                Err(ApiError(err))
+            }
+        }
+    }
+
+    /// Stream a bulk search result from a task in the given format into `writer` and return the number of bytes written.
+    /// > **Warning** task must be in DONE state to be downloaded successfully
+    pub async fn download_bulk_search_to<W: AsyncWrite + Unpin>(&self, uuid: TaskUuid, format: ExportFormat, writer: &mut W) -> Result<u64, DatalakeError> {
+        let mut resp = self.bulk_search_download_response(&uuid, format, 0).await?;
+        let mut bytes_written = 0;
+        while let Some(chunk) = resp.chunk().await? {
+            writer.write_all(&chunk).await?;
+            bytes_written += chunk.len() as u64;
+        }
+        writer.flush().await?;
+        Ok(bytes_written)
+    }
+
+    /// Create a bulk search task, wait for it then stream its result to a file, see [AsyncDatalake::bulk_search]
+    pub async fn bulk_search_to_file(&self, query_hash: String, query_fields: Vec<String>, path: &Path) -> Result<u64, DatalakeError> {
+        self.start_bulk_search(query_hash, query_fields).await?.wait_to_file(path, |_| {}).await
+    }
+
+    /// Download to a temporary file renamed once complete, so no partial file is left at `path`
+    ///
+    /// The download is resumed with a Range request if the connection drops, and its size is checked if known.
+    async fn download_bulk_search_to_file(&self, uuid: TaskUuid, format: ExportFormat, path: &Path, expected_size: Option<u64>) -> Result<u64, DatalakeError> {
+        let mut part_path = path.as_os_str().to_owned();
+        part_path.push(PARTIAL_DOWNLOAD_SUFFIX);
+        let part_path = PathBuf::from(part_path);
+        let io_error = |err: std::io::Error| IoError(DetailedError::new(format!("could not write {}: {err}", part_path.display())));
+
+        let download = async {
+            let mut file = File::create(&part_path).await.map_err(io_error)?;
+            let bytes_written = self.download_with_resume(&uuid, format, &mut file).await?;
+            if let Some(expected_size) = expected_size.filter(|expected_size| *expected_size != bytes_written) {
+                let summary = format!("bulk search with task uuid: {uuid} downloaded {bytes_written} bytes instead of {expected_size}");
+                return Err(ApiError(DetailedError::new(summary)));
+            }
+            file.sync_all().await.map_err(io_error)?;
+            fs::rename(&part_path, path).await.map_err(io_error)?;
+            Ok(bytes_written)
         };
-        return Err(ApiError(err));
-    }
-    if resp.error_for_status_ref().is_err() {
-        let err = DetailedError {
-            summary: format!("bulk search with task uuid: {uuid} returned error code {status_code}"),
-            api_url: Some(url),
-            api_response: match resp.text() {
-                Ok(r) => Some(r),
-                Err(_) => None
-            },
-            api_status_code: Some(status_code),
+        let result = download.await;
+        if result.is_err() {
+            let _ = fs::remove_file(&part_path).await;  // The file may not even be created yet
+        }
+        result
+    }
+
+    /// Download into `file`, retrying with an exponential backoff from the last byte received on network and server errors
+    async fn download_with_resume(&self, uuid: &TaskUuid, format: ExportFormat, file: &mut File) -> Result<u64, DatalakeError> {
+        let mut retries = 0;
+        loop {
+            let range_start = file.metadata().await?.len();
+            let err = match self.download_range_to_file(uuid, format, range_start, file).await {
+                Ok(bytes_written) => return Ok(bytes_written),
+                Err(err) => err,
+            };
+            if retries >= DOWNLOAD_MAX_RETRIES || !Self::is_download_retryable(&err) {
+                return Err(err);
+            }
+            retries += 1;
+            let delay = self.settings.bulk_search_retry_interval_sec * 2_u64.pow(retries - 1);
+            warn!("Bulk search download interrupted ({err}), resuming in {delay}s (retry {retries}/{DOWNLOAD_MAX_RETRIES})");
+            self.clock.sleep(Duration::from_secs(delay)).await;
+        }
+    }
+
+    /// Download the result from `range_start` to the end of `file` and return the file size
+    async fn download_range_to_file(&self, uuid: &TaskUuid, format: ExportFormat, range_start: u64, file: &mut File) -> Result<u64, DatalakeError> {
+        let mut resp = self.bulk_search_download_response(uuid, format, range_start).await?;
+        let mut file_size = match resp.status() {
+            StatusCode::PARTIAL_CONTENT => range_start,
+            StatusCode::RANGE_NOT_SATISFIABLE => return Ok(range_start),  // Everything was already received
+            _ => {
+                file.set_len(0).await?;  // Range is not supported, the whole result is sent again
+                0
+            }
         };
-        return Err(ApiError(err));
+        file.seek(SeekFrom::Start(file_size)).await?;
+        // With compression enabled, reqwest reports a dropped connection as a decoding error
+        let interrupted = |err: reqwest::Error| HttpError(DetailedError::new(format!("bulk search download interrupted: {err}")));
+        while let Some(chunk) = resp.chunk().await.map_err(interrupted)? {
+            file.write_all(&chunk).await?;
+            file_size += chunk.len() as u64;
+        }
+        Ok(file_size)
+    }
+
+    /// Network errors and server errors are worth retrying, others would fail again
+    fn is_download_retryable(err: &DatalakeError) -> bool {
+        match err {
+            HttpError(_) => true,
+            ApiError(detailed_error) => detailed_error.api_status_code.is_some_and(|status| status.is_server_error()),
+            _ => false,
+        }
+    }
+
+    /// Send the download request of a bulk search, failing if its result is not available
+    ///
+    /// A non-zero `range_start` asks for the result from that byte only, a 416 status is then not an error.
+    async fn bulk_search_download_response(&self, uuid: &TaskUuid, format: ExportFormat, range_start: u64) -> Result<Response, DatalakeError> {
+        let url = self.settings.routes().bulk_search_download.replace("{task_uuid}", uuid);
+        let mut request = self.client.get(&url)
+            .timeout(Duration::from_secs(BULK_SEARCH_DOWNLOAD_TIMEOUT))
+            .header("Accept", format.mime_type());
+        if range_start > 0 {
+            request = request.header(RANGE, format!("bytes={range_start}-"));
+        }
+        let resp = self.run_with_authorization_token(request).await?;
+        let status_code = resp.status();
+        if range_start > 0 && status_code == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(resp);
+        }
+
+        if status_code == 202 {
+            let err = DetailedError {
+                summary: format!("bulk search with task uuid: {uuid} is not ready to be downloaded"),
+                api_url: Some(url),
+                api_response: resp.text().await.ok(),
+                api_status_code: Some(status_code),
+            };
+            return Err(ApiError(err));
+        }
+        if resp.error_for_status_ref().is_err() {
+            let err = DetailedError {
+                summary: format!("bulk search with task uuid: {uuid} returned error code {status_code}"),
+                api_url: Some(url),
+                api_response: resp.text().await.ok(),
+                api_status_code: Some(status_code),
+            };
+            return Err(ApiError(err));
+        }
+        Ok(resp)
+    }
+
+    /// List every bulk search task of the account matching the filter
+    pub async fn list_bulk_search_tasks(&self, filter: &BulkSearchTaskFilter) -> Result<Vec<BulkSearchTask>, DatalakeError> {
+        let url = self.settings.routes().bulk_search_task.clone();
+        let mut tasks = Vec::new();
+        let mut offset = 0;
+        loop {
+            let request = self.client.post(&url)
+                .header("Accept", "application/json")
+                .json(&json!({"limit": BULK_SEARCH_TASKS_PAGE_SIZE, "offset": offset}));
+            let resp = self.run_with_authorization_token(request).await?;
+            let status_code = resp.status();
+            let json_response = resp.json::<Value>().await?;
+            let api_response = Some(json_response.to_string());
+            let Ok(page) = serde_json::from_value::<BulkSearchTaskPage>(json_response) else {
+                let summary = "bulk search task API response not as expected".to_string();
+                return Err(ApiError(DetailedError { summary, api_url: Some(url), api_response, api_status_code: Some(status_code) }));
+            };
+            offset += page.results.len();
+            let is_last_page = page.results.is_empty() || offset >= page.count;
+            tasks.extend(page.results.into_iter().filter(|task| filter.matches(task)));
+            if is_last_page {
+                return Ok(tasks);
+            }
+        }
+    }
+
+    /// Cancel a bulk search task that is not finished yet
+    pub async fn cancel_bulk_search_task(&self, uuid: TaskUuid) -> Result<(), DatalakeError> {
+        let url = self.settings.routes().bulk_search_cancel.replace("{task_uuid}", &uuid);
+        let request = self.client.post(&url)
+            .header("Accept", "application/json")
+            .json(&json!({}));
+        let resp = self.run_with_authorization_token(request).await?;
+        let status_code = resp.status();
+        if resp.error_for_status_ref().is_err() {
+            let err = DetailedError {
+                summary: format!("bulk search with task uuid: {uuid} could not be cancelled, error code {status_code}"),
+                api_url: Some(url),
+                api_response: resp.text().await.ok(),
+                api_status_code: Some(status_code),
+            };
+            return Err(ApiError(err));
+        }
+        Ok(())
     }
-    Ok(resp.text()?)
 }
 
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use serde_json::json;
+    use crate::atom::AtomType;
+    use crate::bulk_search::{BulkSearchRecord, BulkSearchTask, BulkSearchTaskFilter, QueryField};
+    use crate::threat::ThreatType;
+
+    fn task(query_fields: &[&str], file_deleted: bool, file_delete_after: Option<&str>) -> BulkSearchTask {
+        serde_json::from_value(json!({
+            "uuid": "task_uuid123",
+            "state": "DONE",
+            "created_at": "2022-08-22T07:11:32Z",
+            "bulk_search": {"advanced_query_hash": "query_hash123", "query_fields": query_fields},
+            "file_deleted": file_deleted,
+            "file_delete_after": file_delete_after,
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_filter_on_query_fields_and_file_availability() {
+        let now = "2022-08-24T00:00:00Z".parse().unwrap();
+        let filter = BulkSearchTaskFilter {
+            query_fields: Some(vec!["atom_type".to_string(), "atom_value".to_string()]),
+            file_available_at: Some(now),
+            ..Default::default()
+        };
+
+        assert!(filter.matches(&task(&["atom_value", "atom_type"], false, Some("2022-08-25T00:00:00Z"))));
+        assert!(filter.matches(&task(&["atom_value", "atom_type"], false, None)));
+        assert!(!filter.matches(&task(&["atom_value"], false, None)), "missing atom_type column");
+        assert!(!filter.matches(&task(&["atom_value", "atom_type"], true, None)), "file deleted");
+        assert!(!filter.matches(&task(&["atom_value", "atom_type"], false, Some("2022-08-23T00:00:00Z"))), "file expired");
+    }
+
+    #[test]
+    fn test_query_field_names() {
+        for name in ["atom_value", "threat_hashkey", "malware.score.risk", ".hashes.sha256"] {
+            assert_eq!(QueryField::from_str(name).unwrap().to_string(), name);
+        }
+        assert_eq!(QueryField::from_str("phishing.score.risk").unwrap(), QueryField::Score(ThreatType::Phishing));
+        let err = QueryField::parse_all(&["atom_value".to_string(), "atom_valeu".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "Parse Error unknown bulk search query field: atom_valeu");
+        assert!(QueryField::parse_all(&[]).is_err());
+    }
+
+    #[test]
+    fn test_records_from_csv_follow_query_fields() {
+        let csv = "hashes.md5,atom_value,atom_type,malware.score.risk,tags,unrequested\n\
+        d41d8cd98f00b204e9800998ecf8427e,abc,file,42,\"apt, ransomware\",ignored\n\
+        ,8.8.8.8,ip,,,\n";
+        let query_fields = [QueryField::AtomValue, QueryField::AtomType, QueryField::Score(ThreatType::Malware), QueryField::Tags, QueryField::Md5];
+
+        let records = BulkSearchRecord::from_csv(csv, &query_fields).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].atom_value.as_deref(), Some("abc"));
+        assert_eq!(records[0].atom_type, Some(AtomType::File));
+        assert_eq!(records[0].extra["malware.score.risk"], json!(42));
+        assert_eq!(records[0].extra["tags"], json!(["apt", "ransomware"]));
+        assert_eq!(records[0].extra[".hashes.md5"], json!("d41d8cd98f00b204e9800998ecf8427e"));
+        assert!(!records[0].extra.contains_key("unrequested"));
+        assert!(records[1].extra.is_empty());
+
+        let err = BulkSearchRecord::from_csv(csv, &[QueryField::ThreatHashkey]).unwrap_err();
+        assert_eq!(err.to_string(), "API Error bulk search CSV has no threat_hashkey column");
+    }
+}
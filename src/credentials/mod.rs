@@ -0,0 +1,309 @@
+use std::env;
+use std::fmt;
+use std::fmt::Debug;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::Command;
+use serde::Deserialize;
+use crate::{DatalakeError, DetailedError};
+use crate::DatalakeError::{AuthenticationError, IoError, ParseError};
+use crate::secret::{Secret, REDACTED};
+
+const DEFAULT_ENV_PREFIX: &str = "OCD_DTL_RS_";
+
+/// Credentials supplied by a [CredentialProvider]
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    UsernamePassword { username: String, password: Secret },
+    LongtermToken(Secret),
+}
+
+impl Credentials {
+    /// A long-term token overwrites the username and password, like with the builder
+    fn from_parts(
+        username: Option<String>,
+        password: Option<String>,
+        longterm_token: Option<String>,
+        source: &str,
+    ) -> Result<Option<Self>, DatalakeError> {
+        let non_empty = |value: Option<String>| value.filter(|value| !value.is_empty());
+        match (non_empty(username), non_empty(password), non_empty(longterm_token)) {
+            (_, _, Some(longterm_token)) => Ok(Some(Credentials::LongtermToken(longterm_token.into()))),
+            (Some(username), Some(password), None) => Ok(Some(Credentials::UsernamePassword { username, password: password.into() })),
+            (None, None, None) => Ok(None),
+            _ => {
+                let summary = format!("{source} must provide the username and password together");
+                Err(AuthenticationError(DetailedError::new(summary)))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::UsernamePassword { password, .. } => f.debug_struct("UsernamePassword")
+                .field("username", &REDACTED)
+                .field("password", password)
+                .finish(),
+            Credentials::LongtermToken(longterm_token) => f.debug_tuple("LongtermToken").field(longterm_token).finish(),
+        }
+    }
+}
+
+/// Source of credentials, chained in the builder with [crate::builder::DatalakeBuilder::credential_provider]
+pub trait CredentialProvider: Debug + Send + Sync {
+    /// Reported as the source of the credentials, see [crate::AsyncDatalake::credentials_source]
+    fn name(&self) -> String;
+
+    /// `Ok(None)` when this provider has no credentials, so the next provider is tried
+    fn credentials(&self) -> Result<Option<Credentials>, DatalakeError>;
+}
+
+/// Reads `OCD_DTL_RS_USERNAME` and `OCD_DTL_RS_PASSWORD`, or `OCD_DTL_RS_LONGTERM_TOKEN`
+#[derive(Debug, Clone)]
+pub struct EnvCredentialProvider {
+    prefix: String,
+}
+
+impl EnvCredentialProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read `<prefix>USERNAME`, `<prefix>PASSWORD` and `<prefix>LONGTERM_TOKEN` instead
+    pub fn with_prefix(prefix: impl Into<String>) -> Self {
+        EnvCredentialProvider { prefix: prefix.into() }
+    }
+}
+
+impl Default for EnvCredentialProvider {
+    fn default() -> Self {
+        Self::with_prefix(DEFAULT_ENV_PREFIX)
+    }
+}
+
+impl CredentialProvider for EnvCredentialProvider {
+    fn name(&self) -> String {
+        format!("environment variables {}*", self.prefix)
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>, DatalakeError> {
+        let var = |name: &str| env::var(format!("{}{name}", self.prefix)).ok();
+        Credentials::from_parts(var("USERNAME"), var("PASSWORD"), var("LONGTERM_TOKEN"), &self.name())
+    }
+}
+
+/// Reads the `username` and `password` files, or the `longterm_token` file, of a directory
+///
+/// Meant for secrets mounted by Docker (`/run/secrets`) or Kubernetes, a trailing newline is ignored.
+#[derive(Debug, Clone)]
+pub struct FileCredentialProvider {
+    directory: PathBuf,
+}
+
+impl FileCredentialProvider {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        FileCredentialProvider { directory: directory.into() }
+    }
+
+    fn read(&self, file_name: &str) -> Result<Option<String>, DatalakeError> {
+        let path = self.directory.join(file_name);
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Some(content.trim_end_matches(['\n', '\r']).to_string())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(IoError(DetailedError::new(format!("Could not read credentials file {} : {err}", path.display())))),
+        }
+    }
+}
+
+impl CredentialProvider for FileCredentialProvider {
+    fn name(&self) -> String {
+        format!("files in {}", self.directory.display())
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>, DatalakeError> {
+        Credentials::from_parts(self.read("username")?, self.read("password")?, self.read("longterm_token")?, &self.name())
+    }
+}
+
+/// Keys expected in the output of a [CommandCredentialProvider]
+#[derive(Deserialize)]
+struct CommandOutput {
+    username: Option<String>,
+    password: Option<String>,
+    longterm_token: Option<String>,
+}
+
+/// Runs a command, like a vault CLI, printing a JSON object with `username` and `password` or `longterm_token`
+///
+/// ```no_run
+/// use ocd_datalake_rs::credentials::CommandCredentialProvider;
+///
+/// let provider = CommandCredentialProvider::new("vault", ["kv", "get", "-format=json", "-field=data", "secret/datalake"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct CommandCredentialProvider {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandCredentialProvider {
+    pub fn new<I, S>(program: impl Into<String>, args: I) -> Self
+        where I: IntoIterator<Item=S>, S: Into<String> {
+        CommandCredentialProvider { program: program.into(), args: args.into_iter().map(Into::into).collect() }
+    }
+}
+
+impl CredentialProvider for CommandCredentialProvider {
+    fn name(&self) -> String {
+        format!("command {}", self.program)
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>, DatalakeError> {
+        let output = Command::new(&self.program).args(&self.args).output()
+            .map_err(|err| IoError(DetailedError::new(format!("Could not run credentials {} : {err}", self.name()))))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let summary = format!("credentials {} failed with {} : {}", self.name(), output.status, stderr.trim());
+            return Err(AuthenticationError(DetailedError::new(summary)));
+        }
+        // The output is not kept in the error as it may hold the credentials
+        let parsed: CommandOutput = serde_json::from_slice(&output.stdout)
+            .map_err(|_| ParseError(DetailedError::new(format!("credentials {} did not print a JSON object", self.name()))))?;
+        Credentials::from_parts(parsed.username, parsed.password, parsed.longterm_token, &self.name())
+    }
+}
+
+/// User of the keyring entry holding a long-term token
+#[cfg(feature = "keyring")]
+const KEYRING_LONGTERM_TOKEN_USER: &str = "longterm_token";
+
+/// Reads the OS keyring: macOS Keychain, Windows Credential Manager or Linux kernel keyutils
+///
+/// Only available with the `keyring` feature.
+#[cfg(feature = "keyring")]
+#[derive(Debug, Clone)]
+pub struct KeyringCredentialProvider {
+    service: String,
+    username: Option<String>,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringCredentialProvider {
+    /// Password saved in the keyring for the given service and username
+    pub fn username_password(service: impl Into<String>, username: impl Into<String>) -> Self {
+        KeyringCredentialProvider { service: service.into(), username: Some(username.into()) }
+    }
+
+    /// Long-term token saved in the keyring for the given service, under the `longterm_token` user
+    pub fn longterm_token(service: impl Into<String>) -> Self {
+        KeyringCredentialProvider { service: service.into(), username: None }
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl CredentialProvider for KeyringCredentialProvider {
+    fn name(&self) -> String {
+        format!("keyring service {}", self.service)
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>, DatalakeError> {
+        let user = self.username.as_deref().unwrap_or(KEYRING_LONGTERM_TOKEN_USER);
+        let secret = keyring::Entry::new(&self.service, user).and_then(|entry| entry.get_password());
+        let secret = match secret {
+            Ok(secret) => secret,
+            Err(keyring::Error::NoEntry) => return Ok(None),
+            Err(err) => return Err(AuthenticationError(DetailedError::new(format!("Could not read the {} : {err}", self.name())))),
+        };
+        match &self.username {
+            Some(username) => Credentials::from_parts(Some(username.clone()), Some(secret), None, &self.name()),
+            None => Credentials::from_parts(None, None, Some(secret), &self.name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use crate::credentials::{CommandCredentialProvider, CredentialProvider, Credentials, EnvCredentialProvider, FileCredentialProvider};
+    use crate::tests::ENV_MUTEX;
+
+    fn username_password(username: &str, password: &str) -> Credentials {
+        Credentials::UsernamePassword { username: username.to_string(), password: password.into() }
+    }
+
+    #[test]
+    fn test_env_credential_provider() {
+        let _mutex = ENV_MUTEX.lock().unwrap();
+        let provider = EnvCredentialProvider::with_prefix("OCD_DTL_RS_TEST_ENV_");
+        assert_eq!(provider.credentials().unwrap(), None);
+
+        env::set_var("OCD_DTL_RS_TEST_ENV_USERNAME", "username");
+        let err = provider.credentials().unwrap_err();
+        assert_eq!(err.to_string(), "Authentication Error environment variables OCD_DTL_RS_TEST_ENV_* must provide the username and password together");
+
+        env::set_var("OCD_DTL_RS_TEST_ENV_PASSWORD", "password");
+        assert_eq!(provider.credentials().unwrap(), Some(username_password("username", "password")));
+
+        env::set_var("OCD_DTL_RS_TEST_ENV_LONGTERM_TOKEN", "longterm_token");
+        assert_eq!(provider.credentials().unwrap(), Some(Credentials::LongtermToken("longterm_token".into())));
+
+        for name in ["USERNAME", "PASSWORD", "LONGTERM_TOKEN"] {
+            env::remove_var(format!("OCD_DTL_RS_TEST_ENV_{name}"));
+        }
+    }
+
+    #[test]
+    fn test_file_credential_provider() {
+        let directory = tempfile::tempdir().unwrap();
+        let provider = FileCredentialProvider::new(directory.path());
+        assert_eq!(provider.credentials().unwrap(), None);
+
+        fs::write(directory.path().join("username"), "username\n").unwrap();
+        fs::write(directory.path().join("password"), "pass word\n").unwrap();
+
+        assert_eq!(provider.credentials().unwrap(), Some(username_password("username", "pass word")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_credential_provider() {
+        let provider = CommandCredentialProvider::new("sh", ["-c", r#"echo '{"longterm_token": "longterm_token"}'"#]);
+        assert_eq!(provider.credentials().unwrap(), Some(Credentials::LongtermToken("longterm_token".into())));
+
+        let failing_provider = CommandCredentialProvider::new("sh", ["-c", "echo 'permission denied' >&2; exit 2"]);
+        let err = failing_provider.credentials().unwrap_err();
+        assert_eq!(err.to_string(), "Authentication Error credentials command sh failed with exit status: 2 : permission denied");
+
+        let invalid_provider = CommandCredentialProvider::new("sh", ["-c", "echo secret"]);
+        let err = invalid_provider.credentials().unwrap_err();
+        assert_eq!(err.to_string(), "Parse Error credentials command sh did not print a JSON object");
+    }
+
+    #[cfg(feature = "keyring")]
+    #[test]
+    fn test_keyring_credential_provider() {
+        use crate::credentials::KeyringCredentialProvider;
+        let service = format!("ocd_dtl_rs_test_{}", std::process::id());
+        let provider = KeyringCredentialProvider::username_password(&service, "username");
+        assert_eq!(provider.credentials().unwrap(), None);
+
+        let entry = keyring::Entry::new(&service, "username").unwrap();
+        entry.set_password("password").unwrap();
+        let credentials = provider.credentials();
+        entry.delete_credential().unwrap();
+
+        assert_eq!(credentials.unwrap(), Some(username_password("username", "password")));
+        assert_eq!(KeyringCredentialProvider::longterm_token(&service).credentials().unwrap(), None);
+        assert_eq!(provider.name(), format!("keyring service {service}"));
+    }
+
+    #[test]
+    fn test_credentials_are_redacted() {
+        let credentials = username_password("username", "password");
+        assert_eq!(format!("{credentials:?}"), r#"UsernamePassword { username: "[REDACTED]", password: [REDACTED] }"#);
+    }
+}
@@ -2,7 +2,7 @@ use std::error::Error;
 use std::fmt;
 use reqwest::StatusCode;
 use crate::DatalakeError::TimeoutError;
-use crate::error::DatalakeError::{ApiError, AuthenticationError, HttpError, ParseError, UnexpectedLibError, ProxyError};
+use crate::error::DatalakeError::{ApiError, AuthenticationError, ClientError, ConfigError, HttpError, ParseError, UnexpectedLibError, ProxyError, IoError};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct DetailedError {
@@ -23,6 +23,24 @@ impl DetailedError {
     }
 }
 
+/// Error in a configuration, pointing at the offending key and line when they are known
+#[derive(Debug, PartialEq, Eq)]
+pub struct DetailedConfigError {
+    pub summary: String,
+    pub key: Option<String>,
+    pub line: Option<usize>,
+}
+
+impl DetailedConfigError {
+    pub fn new(summary: String) -> Self {
+        DetailedConfigError {
+            summary,
+            key: None,
+            line: None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum DatalakeError {
     AuthenticationError(DetailedError),
@@ -32,6 +50,9 @@ pub enum DatalakeError {
     TimeoutError(DetailedError),
     ParseError(DetailedError),
     UnexpectedLibError(DetailedError),
+    ConfigError(DetailedConfigError),
+    IoError(DetailedError),
+    ClientError(DetailedError),  // HTTP client could not be built, like on an invalid CA certificate
 }
 
 
@@ -41,6 +62,19 @@ impl fmt::Display for DetailedError {
     }
 }
 
+impl fmt::Display for DetailedConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.summary)?;
+        if let Some(key) = &self.key {
+            write!(f, " (key: {key})")?;
+        }
+        if let Some(line) = self.line {
+            write!(f, " (line: {line})")?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for DatalakeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -51,6 +85,9 @@ impl fmt::Display for DatalakeError {
             ApiError(err) => write!(f, "API Error {}", err),
             ParseError(err) => write!(f, "Parse Error {}", err),
             UnexpectedLibError(err) => write!(f, "Unexpected Library Error {}", err),
+            ConfigError(err) => write!(f, "Config Error {}", err),
+            IoError(err) => write!(f, "IO Error {}", err),
+            ClientError(err) => write!(f, "Client Error {}", err),
         }
     }
 }
@@ -74,6 +111,12 @@ impl From<reqwest::Error> for DatalakeError {
     }
 }
 
+impl From<std::io::Error> for DatalakeError {
+    fn from(error: std::io::Error) -> Self {
+        IoError(DetailedError::new(error.to_string()))
+    }
+}
+
 impl From<strum::ParseError> for DatalakeError {
     fn from(error: strum::ParseError) -> Self {
         let unexpected_state = error.source().unwrap().to_string();
@@ -3,101 +3,223 @@ extern crate core;
 pub mod setting;
 pub mod error;
 pub mod bulk_search;
+pub mod builder;
+pub mod threat;
+pub mod advanced_search;
+pub mod atom;
+pub mod query;
+pub mod bulk_lookup;
+pub mod polling;
+pub mod token_store;
+pub mod secret;
+pub mod credentials;
+pub mod longterm_token;
 
 use std::collections::{BTreeMap, HashMap};
-use std::thread;
-use std::time::{Duration, Instant};
-use log::{info, debug};
-use std::env;
-use reqwest::Proxy;
-use reqwest::blocking::{Client, RequestBuilder, Response};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use log::{info, warn};
+use reqwest::{Client, RequestBuilder, Response};
 use reqwest::header::AUTHORIZATION;
 use serde_json::{json, Map, Value};
-use crate::bulk_search::{create_bulk_search_task, download_bulk_search, get_bulk_search_task, State};
+use tokio::runtime::Runtime;
+use tokio::sync::RwLock;
+use crate::atom::{AtomType, HashType};
+use crate::polling::Clock;
+use crate::secret::{redact_json, Secret, REDACTED};
+use crate::token_store::{StoredTokens, TokenStore};
 use crate::error::{DatalakeError, DetailedError};
-use crate::DatalakeError::{ApiError, AuthenticationError, TimeoutError, ProxyError, UnexpectedLibError};
-pub use crate::setting::{DatalakeSetting, RoutesSetting};
+use crate::DatalakeError::{ApiError, AuthenticationError, TimeoutError, UnexpectedLibError};
+pub use crate::builder::DatalakeBuilder;
+pub use crate::polling::PollingStrategy;
+pub use crate::setting::{DatalakeSetting, ProxySetting, RoutesSetting};
 
+/// Name of [bulk_search::QueryField::AtomValue]
 pub const ATOM_VALUE_QUERY_FIELD: &str = "atom_value";
 
 #[derive(Clone, Debug)]
 struct Tokens {  // Tokens are saved with the "Token " prefix
-    access: String,
-    refresh: String,
+    access: Secret,
+    refresh: Secret,
+    access_expires_at: Option<DateTime<Utc>>,  // None if the access token is not a JWT
+    access_refresh_after: Option<DateTime<Utc>>,  // Access token is refreshed proactively after this date
 }
 
-#[derive(Clone, Debug)]
-pub struct Datalake {
+impl Tokens {
+    /// The access token is refreshed `skew` before it expires, or halfway through its lifetime if that is shorter,
+    /// so a token living less than `skew` isn't refreshed on every call
+    fn new(access: Secret, refresh: Secret, skew: chrono::Duration, now: DateTime<Utc>) -> Self {
+        let validity = jwt_validity(access.expose());
+        let access_expires_at = validity.map(|(_, expires_at)| expires_at);
+        let access_refresh_after = validity.map(|(issued_at, expires_at)| {
+            let lifetime = (expires_at - issued_at.unwrap_or(now)).max(chrono::Duration::zero());
+            expires_at - skew.min(lifetime / 2)
+        });
+        Tokens { access, refresh, access_expires_at, access_refresh_after }
+    }
+}
+
+/// Issue date and expiry of a JWT from its iat and exp claims, the signature is left to the API to check
+fn jwt_validity(token: &str) -> Option<(Option<DateTime<Utc>>, DateTime<Utc>)> {
+    let jwt = token.strip_prefix("Token ").unwrap_or(token);
+    let payload = jwt.split('.').nth(1)?;
+    let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?).ok()?;
+    let issued_at = claims.get("iat").and_then(Value::as_i64).and_then(|iat| DateTime::from_timestamp(iat, 0));
+    Some((issued_at, DateTime::from_timestamp(claims.get("exp")?.as_i64()?, 0)?))
+}
+
+/// Async client, to be used from within a tokio runtime
+///
+/// Clones share the same session, so a single login is made for all of them
+#[derive(Clone)]
+pub struct AsyncDatalake {
     settings: DatalakeSetting,
     username: Option<String>,
-    password: Option<String>,
-    longterm_token: Option<String>,
+    password: Option<Secret>,
+    longterm_token: Option<Secret>,
     client: Client,
-    tokens: Option<Tokens>,
+    tokens: Arc<RwLock<Option<Tokens>>>,
+    clock: Arc<dyn Clock>,
+    token_store: Option<Arc<dyn TokenStore>>,
+    credentials_source: String,
 }
 
-fn build_client() -> Result<Client, DatalakeError> {
-    let https_proxy = env::var("HTTPS_PROXY").ok();
-    let http_proxy = env::var("HTTP_PROXY").ok();
-    let client_builder = Client::builder();
-    
-    // Try HTTPS_PROXY first, then HTTP_PROXY, otherwise use default client
-    if let Some(url) = https_proxy.or(http_proxy) {
-        debug!("{}", format!("Using Proxy : {url}"));
-        if url.starts_with("http") {
-            match Proxy::all(&url) {
-                Ok(proxy) => client_builder
-                    .proxy(proxy)
-                    .build()
-                    .map_err(|e| ProxyError(DetailedError::new(format!("Failed to build client : {e}")))),
-                Err(e) => Err(ProxyError(DetailedError::new(format!("Invalid proxy URL : {e}"))))
-            }
-        } else {
-            Err(ProxyError(DetailedError::new(format!("Invalid proxy URL : {url}"))))
-        }
-    } else {
-        debug!("No proxies configured, using default client. To specify a proxy, please set the HTTP_PROXY(S) env variable.");
-        Ok(Client::new())
+impl fmt::Debug for AsyncDatalake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let redacted = |is_set: bool| is_set.then_some(REDACTED);
+        f.debug_struct("AsyncDatalake")
+            .field("settings", &self.settings)
+            .field("username", &redacted(self.username.is_some()))
+            .field("password", &self.password)
+            .field("longterm_token", &self.longterm_token)
+            .field("tokens", &self.tokens)
+            .field("token_store", &self.token_store)
+            .field("credentials_source", &self.credentials_source)
+            .finish_non_exhaustive()
     }
 }
 
+/// Blocking client, a thin wrapper running an [AsyncDatalake] on a runtime shared by every blocking client
+///
+/// It can be shared across threads through an `Arc`, requests from different threads are run concurrently.
+/// > **Warning** like `reqwest::blocking`, it panics if called from within an async runtime, use [AsyncDatalake] there.
+/// > Dropping it is fine anywhere, the shared runtime lives as long as the process.
+#[derive(Clone, Debug)]
+pub struct Datalake {
+    inner: AsyncDatalake,
+    runtime: &'static Runtime,
+}
+
 impl Datalake {
+    /// Shortcut over [Datalake::builder]
     pub fn new(
         username: Option<String>,
         password: Option<String>,
         longterm_token: Option<String>,
         settings: DatalakeSetting
     ) -> Result<Self, String> {
-        if (username.is_some() && password.is_some()) || longterm_token.is_some() {
-            Ok(Datalake {
-                settings,
-                username,
-                password,
-                longterm_token,
-                client: Client::new(),
-                tokens: None,
-            })
-        } else {
-            Err("Either username & password must be provided together or longterm_token must be present.".to_string())
+        Self::builder_from(username, password, longterm_token, settings)
+            .build()
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn builder() -> DatalakeBuilder {
+        DatalakeBuilder::default()
+    }
+
+    fn builder_from(
+        username: Option<String>,
+        password: Option<String>,
+        longterm_token: Option<String>,
+        settings: DatalakeSetting
+    ) -> DatalakeBuilder {
+        let mut builder = Self::builder().setting(settings);
+        if let (Some(username), Some(password)) = (username, password) {
+            builder = builder.credentials(username, password);
+        }
+        if let Some(longterm_token) = longterm_token {
+            builder = builder.longterm_token(longterm_token);
         }
+        builder
+    }
+
+    /// Cached version of retrieve_api_token that return a new token only if needed
+    pub fn get_access_token(&self) -> Result<String, DatalakeError> {
+        self.runtime.block_on(self.inner.get_access_token())
+    }
+
+    /// "builder", or the name of the credential provider that supplied the credentials
+    pub fn credentials_source(&self) -> &str {
+        self.inner.credentials_source()
+    }
+
+    /// Return the atom types based on the given atom_values
+    pub fn extract_atom_type(&self, atom_values: &[String], treat_hashes_like: HashType) -> Result<BTreeMap<String, AtomType>, DatalakeError> {
+        self.runtime.block_on(self.inner.extract_atom_type(atom_values, treat_hashes_like))
+    }
+
+    /// Return a CSV of the bulk lookup for given threats
+    ///
+    /// Hashes threat type are defined by treat_hashes_like, other threats have their atom type automatically defined
+    pub fn bulk_lookup(&self, atom_values: Vec<String>, treat_hashes_like: HashType) -> Result<String, DatalakeError> {
+        self.runtime.block_on(self.inner.bulk_lookup(atom_values, treat_hashes_like))
+    }
+
+    /// Retrieve all the results of a query using its query_hash.
+    ///
+    /// Fields returned depend on query_fields.
+    /// For now the result is returned as a CSV.
+    /// > **Warning** the function is blocking while the bulk search is being processed by the API (up to 1h)
+    pub fn bulk_search(&self, query_hash: String, query_fields: Vec<String>) -> Result<String, DatalakeError> {
+        self.runtime.block_on(self.inner.bulk_search(query_hash, query_fields))
+    }
+}
+
+impl AsyncDatalake {
+    /// Shortcut over [AsyncDatalake::builder]
+    pub fn new(
+        username: Option<String>,
+        password: Option<String>,
+        longterm_token: Option<String>,
+        settings: DatalakeSetting
+    ) -> Result<Self, String> {
+        Datalake::builder_from(username, password, longterm_token, settings)
+            .build_async()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Same builder as [Datalake::builder], finished with [DatalakeBuilder::build_async]
+    pub fn builder() -> DatalakeBuilder {
+        DatalakeBuilder::default()
+    }
+
+    /// "builder", or the name of the credential provider that supplied the credentials
+    pub fn credentials_source(&self) -> &str {
+        &self.credentials_source
     }
+
     /// get a refresh and a short-term token (isn't called if a longterm_token was provided)
-    fn retrieve_api_tokens(&self) -> Result<Tokens, DatalakeError> {
+    async fn retrieve_api_tokens(&self) -> Result<Tokens, DatalakeError> {
         let url = &self.settings.routes().authentication;
         let auth_request = self.client.post(url);
         let mut json_body = HashMap::new();
-        json_body.insert("email", &self.username);
-        json_body.insert("password", &self.password);
-        let resp = auth_request.json(&json_body).send()?;
+        json_body.insert("email", self.username.as_deref());
+        json_body.insert("password", self.password.as_ref().map(Secret::expose));
+        let resp = auth_request.json(&json_body).send().await?;
         let status_code = resp.status();
-        let json_resp = resp.json::<Value>()?;
+        let json_resp = resp.json::<Value>().await?;
         let raw_access_token = json_resp["access_token"].as_str();
         let raw_refresh_token = json_resp["refresh_token"].as_str();
         if raw_access_token.is_none() || raw_refresh_token.is_none() {
             let err = DetailedError {
                 summary: "Invalid credentials".to_string(),
                 api_url: Some(url.to_string()),
-                api_response: Some(json_resp.to_string()),
+                api_response: Some(redact_json(&json_resp)),
                 api_status_code: Some(status_code),
             };
             return Err(AuthenticationError(err));
@@ -105,51 +227,129 @@ impl Datalake {
         let access_token = format!("Token {}", raw_access_token.unwrap());
         let refresh_token = format!("Token {}", raw_refresh_token.unwrap());
 
-        Ok(Tokens {
-            access: access_token,
-            refresh: refresh_token,
-        })
+        Ok(self.new_tokens(access_token.into(), refresh_token.into()))
     }
 
     /// Cached version of retrieve_api_token that return a new token only if needed
-    pub fn get_access_token(&mut self) -> Result<String, DatalakeError> {
+    ///
+    /// A JWT access token is refreshed token_refresh_skew_sec before it expires, instead of waiting for a 401
+    pub async fn get_access_token(&self) -> Result<String, DatalakeError> {
         if let Some(ref token) = self.longterm_token {
-            return Ok(format!("Token {}", token));
+            return Ok(format!("Token {}", token.expose()));
+        }
+
+        if let Some(tokens) = self.tokens.read().await.as_ref() {
+            if !self.expires_soon(tokens) {
+                return Ok(tokens.access.expose().to_string());
+            }
+        }
+        let mut tokens = self.tokens.write().await;
+        if tokens.is_none() {
+            *tokens = self.load_stored_tokens();
+        }
+        // Another request may have logged in or refreshed while we were waiting for the lock
+        let new_tokens = match tokens.as_ref() {
+            None => Some(self.retrieve_api_tokens().await?),
+            Some(current_tokens) if self.expires_soon(current_tokens) => {
+                info!("Access token about to expire");
+                match self.refresh_tokens(Some(current_tokens)).await {
+                    Ok(refreshed_tokens) => Some(refreshed_tokens),
+                    // The current token is still valid, the refresh is tried again on the next call
+                    Err(err) if !self.is_expired(current_tokens) => {
+                        warn!("Could not refresh the access token before it expires, using it until then: {err}");
+                        None
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Some(_) => None,
+        };
+        if let Some(new_tokens) = new_tokens {
+            self.store_tokens(&new_tokens);
+            *tokens = Some(new_tokens);
+        }
+        Ok(tokens.as_ref().unwrap().access.expose().to_string())
+    }
+
+    /// Session saved by a previous client in the token store, if any
+    fn load_stored_tokens(&self) -> Option<Tokens> {
+        let (store, username) = (self.token_store.as_ref()?, self.username.as_ref()?);
+        match store.load(self.settings.base_url(), username) {
+            Ok(stored_tokens) => stored_tokens.map(|stored_tokens| self.new_tokens(stored_tokens.access, stored_tokens.refresh)),
+            Err(err) => {
+                warn!("Could not load tokens from the token store, logging in again: {err}");
+                None
+            }
         }
-    
-        if self.tokens.is_none() {
-            self.tokens = Some(self.retrieve_api_tokens()?);
+    }
+
+    /// Save the session for the next clients, a failure only costing them a login
+    fn store_tokens(&self, tokens: &Tokens) {
+        let (Some(store), Some(username)) = (self.token_store.as_ref(), self.username.as_ref()) else {
+            return;
+        };
+        let stored_tokens = StoredTokens { access: tokens.access.clone(), refresh: tokens.refresh.clone() };
+        if let Err(err) = store.save(self.settings.base_url(), username, &stored_tokens) {
+            warn!("Could not save tokens in the token store: {err}");
+        }
+    }
+
+    fn new_tokens(&self, access: Secret, refresh: Secret) -> Tokens {
+        let skew = chrono::Duration::seconds(self.settings.token_refresh_skew_sec as i64);
+        Tokens::new(access, refresh, skew, self.clock.now())
+    }
+
+    fn expires_soon(&self, tokens: &Tokens) -> bool {
+        tokens.access_refresh_after.is_some_and(|refresh_after| refresh_after <= self.clock.now())
+    }
+
+    fn is_expired(&self, tokens: &Tokens) -> bool {
+        tokens.access_expires_at.is_some_and(|expires_at| expires_at <= self.clock.now())
+    }
+
+    /// Replace an expired access token and return the new one.
+    ///
+    /// Only a single refresh is made when concurrent requests got a 401 with the same expired token
+    async fn refresh_access_token(&self, expired_access_token: &str) -> Result<String, DatalakeError> {
+        let mut tokens = self.tokens.write().await;
+        if let Some(current_tokens) = tokens.as_ref() {
+            if current_tokens.access.expose() != expired_access_token {
+                return Ok(current_tokens.access.expose().to_string());  // Already refreshed by another request
+            }
         }
-        let access_token = self.tokens.as_ref().unwrap().clone().access;
+        let refreshed_tokens = self.refresh_tokens(tokens.as_ref()).await?;
+        let access_token = refreshed_tokens.access.expose().to_string();
+        self.store_tokens(&refreshed_tokens);
+        *tokens = Some(refreshed_tokens);
         Ok(access_token)
     }
 
     /// Return valid tokens, first by using the refresh token, then by using user credentials
-    fn refresh_tokens(&self) -> Result<Tokens, DatalakeError> {
+    async fn refresh_tokens(&self, tokens: Option<&Tokens>) -> Result<Tokens, DatalakeError> {
         info!("Refreshing the access token");
         let url = &self.settings.routes().refresh_token;
-        let refresh_token = if let Some(tokens) = &self.tokens {
-            tokens.clone().refresh
+        let refresh_token = if let Some(tokens) = tokens {
+            tokens.refresh.clone()
         } else {
             let error_message = "Refresh tokens called despite no token set".to_string();
             return Err(UnexpectedLibError(DetailedError::new(error_message)));
         };
         let request = self.client.post(url)
-            .header("Authorization", refresh_token.clone());
+            .header("Authorization", refresh_token.expose());
 
-        let resp = request.send()?;
+        let resp = request.send().await?;
         let status_code = resp.status();
         if status_code == 401 {
             info!("Refresh token is expired, authenticating from the start");
-            return self.retrieve_api_tokens();
+            return self.retrieve_api_tokens().await;
         }
-        let json_resp = resp.json::<Value>()?;
+        let json_resp = resp.json::<Value>().await?;
         let access_token = match json_resp["access_token"].as_str() {
             None => {
                 let err = DetailedError {
                     summary: "Invalid credentials".to_string(),
                     api_url: Some(url.to_string()),
-                    api_response: Some(json_resp.to_string()),
+                    api_response: Some(redact_json(&json_resp)),
                     api_status_code: Some(status_code),
                 };
                 return Err(AuthenticationError(err));
@@ -157,14 +357,11 @@ impl Datalake {
             Some(raw_access_token) => format!("Token {}", raw_access_token)
         };
 
-        Ok(Tokens {
-            access: access_token,
-            refresh: refresh_token,
-        })
+        Ok(self.new_tokens(access_token.into(), refresh_token))
     }
 
     /// Return the atom types based on the given atom_values
-    pub fn extract_atom_type(&mut self, atom_values: &[String], treat_hashes_like: &str) -> Result<BTreeMap<String, String>, DatalakeError> {
+    pub async fn extract_atom_type(&self, atom_values: &[String], treat_hashes_like: HashType) -> Result<BTreeMap<String, AtomType>, DatalakeError> {
         let url = self.settings.routes().atom_values_extract.clone();
         let mut request = self.client.post(&url);
         let mut joined_atom_values = String::from(&atom_values[0]);
@@ -174,12 +371,12 @@ impl Datalake {
         }
         let json_body = json!({
             "content": joined_atom_values,
-            "treat_hashes_like": String::from(treat_hashes_like),
+            "treat_hashes_like": treat_hashes_like,
         });
         request = request.json(&json_body);
-        let resp = self.run_with_authorization_token(&request)?;
+        let resp = self.run_with_authorization_token(request).await?;
         let status_code = resp.status();
-        let json_resp = resp.json::<Value>()?;
+        let json_resp = resp.json::<Value>().await?;
         let extracted_atom_types = Self::parse_extract_atom_type_result(&json_resp);
         if let Some(extracted) = extracted_atom_types {
             Ok(extracted)
@@ -195,12 +392,12 @@ impl Datalake {
     }
 
     /// Send a request with an authorization token. If the token is expired, retry once, unless it's a long-term token
-    fn run_with_authorization_token(&mut self, request: &RequestBuilder) -> Result<Response, DatalakeError> {
-        let Some(mut cloned_request) = request.try_clone() else {
-            return Err(UnexpectedLibError(DetailedError::new("Can't clone given request".to_string())))
-        };
-        cloned_request = cloned_request.header(AUTHORIZATION, self.get_access_token()?);
-        let mut response = cloned_request.send()?;
+    ///
+    /// A request that can't be cloned, like one with a streaming body, is sent once with a token refreshed ahead of its expiry.
+    async fn run_with_authorization_token(&self, request: RequestBuilder) -> Result<Response, DatalakeError> {
+        let retry_request = request.try_clone();
+        let access_token = self.get_access_token().await?;
+        let mut response = request.header(AUTHORIZATION, access_token.clone()).send().await?;
         let mut status_code = response.status();
         if status_code != 401 {
             return Ok(response);
@@ -210,25 +407,28 @@ impl Datalake {
             return Err(AuthenticationError(DetailedError {
                 summary: "401 response : invalid long-term token".to_string(),
                 api_url: Some(response.url().to_string()),
-                api_response: response.text().ok(),
+                api_response: response.text().await.ok(),
                 api_status_code: Some(status_code),
             }));
         }
 
         // Else retry
-        self.tokens = Some(self.refresh_tokens()?);
-        let refreshed_token = self.get_access_token()?;
-        let Some(mut retry_request) = request.try_clone() else {
-            return Err(UnexpectedLibError(DetailedError::new("Can't clone given request".to_string())))
+        let refreshed_token = self.refresh_access_token(&access_token).await?;
+        let Some(retry_request) = retry_request else {
+            return Err(AuthenticationError(DetailedError {
+                summary: "401 response on a request that can't be sent again".to_string(),
+                api_url: Some(response.url().to_string()),
+                api_response: response.text().await.ok(),
+                api_status_code: Some(status_code),
+            }));
         };
-        retry_request = retry_request.header(AUTHORIZATION, refreshed_token);
-        response = retry_request.send()?;
+        response = retry_request.header(AUTHORIZATION, refreshed_token).send().await?;
         status_code = response.status();
         if status_code == 401 {
             Err(AuthenticationError(DetailedError {
                 summary: "401 response despite refreshed token".to_string(),
                 api_url: Some(response.url().to_string()),
-                api_response: response.text().ok(),
+                api_response: response.text().await.ok(),
                 api_status_code: Some(status_code),
             }))
         } else {
@@ -236,14 +436,14 @@ impl Datalake {
         }
     }
 
-    fn parse_extract_atom_type_result(json_resp: &Value) -> Option<BTreeMap<String, String>> {
+    fn parse_extract_atom_type_result(json_resp: &Value) -> Option<BTreeMap<String, AtomType>> {
         let results_value = json_resp.get("results")?;
         let results = results_value.as_object()?;
         let mut extracted_atom_types = BTreeMap::new();
         for (atom_type, atoms) in results {
             for atom in atoms.as_array()? {
                 let atom_value = atom.as_str()?.to_string();
-                extracted_atom_types.insert(atom_value, atom_type.clone());
+                extracted_atom_types.insert(atom_value, AtomType::from_str(atom_type).ok()?);
             }
         }
         Some(extracted_atom_types)
@@ -251,28 +451,32 @@ impl Datalake {
 
     /// Return a CSV of the bulk lookup for given threats
     ///
-    /// Hashes threat type are defined by treat_hashes_like, other threats have their atom type automatically defined,
-    /// see Datalake API documentation for possible values
-    pub fn bulk_lookup(&mut self, atom_values: Vec<String>, treat_hashes_like: &str) -> Result<String, DatalakeError> {
+    /// Hashes threat type are defined by treat_hashes_like, other threats have their atom type automatically defined
+    pub async fn bulk_lookup(&self, atom_values: Vec<String>, treat_hashes_like: HashType) -> Result<String, DatalakeError> {
         let mut csv_merged = String::new();
-        for chunk in atom_values.chunks(self.settings.bulk_lookup_chunk_size) {
-            let csv: String = self.bulk_lookup_chunk(chunk, treat_hashes_like)?;
-            if csv_merged.is_empty() {
-                csv_merged = csv;
-            } else {
-                let body = match csv.split_once('\n') {
-                    Some((_header, body)) => body,
-                    None => return Err(Self::csv_without_new_line_error(csv)),
-                };
-                csv_merged = format!("{csv_merged}{body}");
-            }
-            if !csv_merged.ends_with('\n') {
-                csv_merged.push('\n');  // It's easier to merge csv if they always finish with a new line
-            }
+        for csv in self.bulk_lookup_chunks(&atom_values, &treat_hashes_like).await? {
+            Self::append_csv(&mut csv_merged, csv)?;
         }
         Ok(csv_merged)
     }
 
+    /// Add the rows of a chunk CSV to the merged one, whose header is the one of the first chunk
+    pub(crate) fn append_csv(csv_merged: &mut String, csv: String) -> Result<(), DatalakeError> {
+        if csv_merged.is_empty() {
+            *csv_merged = csv;
+        } else {
+            let body = match csv.split_once('\n') {
+                Some((_header, body)) => body,
+                None => return Err(Self::csv_without_new_line_error(csv)),
+            };
+            csv_merged.push_str(body);
+        }
+        if !csv_merged.ends_with('\n') {
+            csv_merged.push('\n');  // It's easier to merge csv if they always finish with a new line
+        }
+        Ok(())
+    }
+
     fn csv_without_new_line_error(csv: String) -> DatalakeError {
         let detailed_error = DetailedError {
             summary: "unexpected csv result, missing body".to_string(),
@@ -283,14 +487,52 @@ impl Datalake {
         ApiError(detailed_error)
     }
 
+    /// CSV of every bulk_lookup_chunk_size chunk of atom_values, in their order
+    ///
+    /// Up to bulk_lookup_parallelism chunks are looked up at once. The first error is returned
+    /// right away and the chunks still running are dropped.
+    pub(crate) async fn bulk_lookup_chunks(&self, atom_values: &[String], treat_hashes_like: &HashType) -> Result<Vec<String>, DatalakeError> {
+        let chunks: Vec<&[String]> = atom_values.chunks(self.settings.bulk_lookup_chunk_size).collect();
+        // Only the chunks looked up before the error are set, the error makes the collect fail
+        self.bulk_lookup_chunks_until(&chunks, treat_hashes_like, |_| true).await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Result of each chunk, in their order, up to bulk_lookup_parallelism chunks being looked up at once
+    ///
+    /// Once a chunk fails with an error for which `stop_on` is true, the chunks still running are dropped
+    /// and the remaining ones are left to None.
+    pub(crate) async fn bulk_lookup_chunks_until<F: Fn(&DatalakeError) -> bool>(
+        &self,
+        chunks: &[&[String]],
+        treat_hashes_like: &HashType,
+        stop_on: F,
+    ) -> Vec<Option<Result<String, DatalakeError>>> {
+        let mut csv_by_chunk: Vec<Option<Result<String, DatalakeError>>> = chunks.iter().map(|_| None).collect();
+        let mut lookups = stream::iter(chunks.iter().enumerate())
+            .map(|(index, chunk)| async move { (index, self.bulk_lookup_chunk(chunk, treat_hashes_like).await) })
+            .buffer_unordered(self.settings.bulk_lookup_parallelism.max(1));  // 0 would never poll a chunk
+        while let Some((index, csv)) = lookups.next().await {
+            let stop = csv.as_ref().is_err_and(&stop_on);
+            csv_by_chunk[index] = Some(csv);
+            if stop {
+                break;
+            }
+        }
+        csv_by_chunk
+    }
+
     /// Bulk lookup a chunk of atom_values
-    fn bulk_lookup_chunk(&mut self, atom_values: &[String], treat_hashes_like: &str) -> Result<String, DatalakeError> {
+    async fn bulk_lookup_chunk(&self, atom_values: &[String], treat_hashes_like: &HashType) -> Result<String, DatalakeError> {
         // Construct the body by identifying the atom types
-        let extracted = self.extract_atom_type(atom_values, treat_hashes_like)?;
+        let extracted = self.extract_atom_type(atom_values, treat_hashes_like.clone()).await?;
         let mut body = Map::new();
         body.insert("hashkey_only".to_string(), Value::Bool(false));
         for (atom_value, atom_type) in extracted {
             let value_to_insert = Value::String(atom_value);
+            let atom_type = atom_type.to_string();
             let entry: Option<&mut Value> = body.get_mut(atom_type.as_str());
             if let Some(atom_value_array) = entry {
                 // Add the atom value to an already existing array
@@ -304,7 +546,7 @@ impl Datalake {
         let request = self.client.post(&self.settings.routes().bulk_lookup)
             .header("Accept", "text/csv")
             .json(&body);
-        let csv_resp = self.run_with_authorization_token(&request)?.text()?;
+        let csv_resp = self.run_with_authorization_token(request).await?.text().await?;
         Ok(csv_resp)
     }
 
@@ -312,66 +554,29 @@ impl Datalake {
     ///
     /// Fields returned depend on query_fields.
     /// For now the result is returned as a CSV.
-    /// > **Warning** the function is blocking while the bulk search is being processed by the API (up to 1h)
-    pub fn bulk_search(&mut self, query_hash: String, query_fields: Vec<String>) -> Result<String, DatalakeError> {
-        let task_uuid = create_bulk_search_task(self, query_hash, query_fields)?;
-        let timeout = self.settings.bulk_search_timeout_sec;
-        let start_time = Instant::now();
-        let mut bulk_search_is_ready = false;
-        while !bulk_search_is_ready {
-            if start_time.elapsed().as_secs() > timeout {
-                let error_summary = format!("Bulk search is not finished after {timeout} seconds");
-                return Err(TimeoutError(DetailedError::new(error_summary)));
-            }
-            thread::sleep(Duration::from_secs(self.settings.bulk_search_retry_interval_sec));
-            let task = get_bulk_search_task(self, task_uuid.clone())?;
-            let state = task.get_state()?;
-            bulk_search_is_ready = match state {
-                State::DONE => true,
-                State::NEW | State::QUEUED | State::IN_PROGRESS => false,  // bulk search is not ready yet
-                State::CANCELLED | State::FAILED_ERROR | State::FAILED_TIMEOUT => {
-                    return Err(ApiError(DetailedError::new(format!("Bulk search ended with {state} state"))));
-                }
-            }
-        }
-        download_bulk_search(self, task_uuid)
+    /// > **Warning** the future only resolves once the bulk search is processed by the API (up to 1h)
+    pub async fn bulk_search(&self, query_hash: String, query_fields: Vec<String>) -> Result<String, DatalakeError> {
+        self.start_bulk_search(query_hash, query_fields).await?.wait_with(|_| {}).await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Datalake, DatalakeSetting};
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use chrono::DateTime;
+    use chrono::Duration;
+    use crate::{jwt_validity, AsyncDatalake, Datalake, DatalakeSetting, Tokens};
+    use crate::secret::Secret;
     use crate::error::DatalakeError::UnexpectedLibError;
     use crate::error::DetailedError;
-    use crate::build_client;
-    use std::env;
+    use std::sync::Mutex;
 
-    #[test]
-    fn test_proxy_client_creation() {
-        env::remove_var("HTTP_PROXY");
-        env::remove_var("HTTPS_PROXY");
-        let result = build_client();
-        assert!(result.is_ok(), "Should build client with no proxy");
-        
-        env::set_var("HTTPS_PROXY", "https://example-proxy-1.com:8080");
-        let result = build_client();
-        assert!(result.is_ok(), "Should build client with valid HTTP proxy");
-        
-        env::remove_var("HTTPS_PROXY");
-        env::set_var("HTTP_PROXY", "http://example-proxy-2.com:8080");
-        let result = build_client();
-        assert!(result.is_ok(), "Should build client with valid HTTP proxy");
-        
-        env::set_var("HTTP_PROXY", "invalid-url");
-        let result = build_client();
-        assert!(result.is_err(), "Should fail with invalid proxy URL");
-        
-        env::remove_var("HTTP_PROXY");
-        env::remove_var("HTTPS_PROXY");
-    }
+    pub(crate) static ENV_MUTEX: Mutex<()> = Mutex::new(());  // Tests reading or modifying env variables, like the proxy or credential ones, should take the mutex
 
     #[test]
     fn test_create_datalake_with_prod_config() {
+        let _mutex = ENV_MUTEX.lock().unwrap();
         let dtl = Datalake::new(
             Some("username".to_string()),
             Some("password".to_string()),
@@ -379,11 +584,12 @@ mod tests {
             DatalakeSetting::prod(),
         ).unwrap();
 
-        assert_eq!(dtl.settings.routes().authentication, "https://datalake.cert.orangecyberdefense.com/api/v3/auth/token/");
+        assert_eq!(dtl.inner.settings.routes().authentication, "https://datalake.cert.orangecyberdefense.com/api/v3/auth/token/");
     }
 
     #[test]
     fn test_create_datalake_preprod_config() {
+        let _mutex = ENV_MUTEX.lock().unwrap();
         let preprod_setting = DatalakeSetting::preprod();
 
         let dtl = Datalake::new(
@@ -393,38 +599,73 @@ mod tests {
             preprod_setting,
         ).unwrap();
 
-        assert_eq!(dtl.settings.routes().authentication, "https://ti2.extranet.mrti-center.com/api/v3/auth/token/");
+        assert_eq!(dtl.inner.settings.routes().authentication, "https://ti2.extranet.mrti-center.com/api/v3/auth/token/");
     }
 
-    #[test]
-    fn test_run_with_authorization_token_fail_on_unclonable_request() {
-        let preprod_setting = DatalakeSetting::preprod();
-        let mut dtl = Datalake::new(
-            Some("username".to_string()),
-            Some("password".to_string()),
-            None,
-            preprod_setting,
-        ).unwrap();
-        // Create a random request
-        let mut request = dtl.client.post(&dtl.settings.routes().authentication);
+    #[tokio::test]
+    async fn test_run_with_authorization_token_sends_unclonable_request_once() {
+        let mut setting = DatalakeSetting::prod();
+        setting.set_base_url(mockito::server_url());
+        let dtl = {
+            let _mutex = ENV_MUTEX.lock().unwrap();
+            AsyncDatalake::new(None, None, Some("longterm_token".to_string()), setting).unwrap()
+        };
+        let streaming_mock = mockito::mock("POST", "/streaming/")
+            .match_header("Authorization", "Token longterm_token")
+            .with_status(200)
+            .create();
         // Set a streaming body that can't be cloned
-        request = request.body(reqwest::blocking::Body::new(std::io::empty()));
-        let err =  dtl.run_with_authorization_token(&request).err().unwrap();
-        let expected_error_message = "Can't clone given request".to_string();
-        assert_eq!(err, UnexpectedLibError(DetailedError::new(expected_error_message)));
+        let stream = futures_util::stream::empty::<Result<Vec<u8>, std::io::Error>>();
+        let request = dtl.client.post(format!("{}/streaming/", mockito::server_url()))
+            .body(reqwest::Body::wrap_stream(stream));
+
+        let response = dtl.run_with_authorization_token(request).await.unwrap();
+
+        streaming_mock.assert();
+        assert_eq!(response.status(), 200);
     }
 
     #[test]
-    fn test_refresh_tokens_with_no_existing_tokens() {
+    fn test_jwt_validity() {
+        let claims = URL_SAFE_NO_PAD.encode(r#"{"fresh":false,"exp":1700000000,"sub":"user"}"#);
+        let jwt = format!("Token eyJhbGciOiJIUzI1NiJ9.{claims}.signature");
+
+        assert_eq!(jwt_validity(&jwt), Some((None, DateTime::from_timestamp(1_700_000_000, 0).unwrap())));
+        assert_eq!(jwt_validity("Token 123"), None);
+        assert_eq!(jwt_validity("Token a.not_base64!.c"), None);
+    }
+
+    #[test]
+    fn test_refresh_skew_capped_by_token_lifetime() {
+        let jwt = |iat: i64, exp: i64| {
+            let claims = URL_SAFE_NO_PAD.encode(format!(r#"{{"iat":{iat},"exp":{exp}}}"#));
+            Secret::from(format!("Token eyJhbGciOiJIUzI1NiJ9.{claims}.signature"))
+        };
+        let skew = Duration::seconds(60);
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let long_lived = Tokens::new(jwt(1_700_000_000, 1_700_003_600), "Token 456".into(), skew, now);
+        assert_eq!(long_lived.access_refresh_after, DateTime::from_timestamp(1_700_003_540, 0));
+        let short_lived = Tokens::new(jwt(1_700_000_000, 1_700_000_030), "Token 456".into(), skew, now);
+        assert_eq!(short_lived.access_refresh_after, DateTime::from_timestamp(1_700_000_015, 0));
+        let no_token = Tokens::new("Token 123".into(), "Token 456".into(), skew, now);
+        assert_eq!(no_token.access_refresh_after, None);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_tokens_with_no_existing_tokens() {
         let preprod_setting = DatalakeSetting::preprod();
-        let dtl = Datalake::new(
-            Some("username".to_string()),
-            Some("password".to_string()),
-            None,
-            preprod_setting,
-        ).unwrap();
-        let err =  dtl.refresh_tokens().err().unwrap();
+        let dtl = {
+            let _mutex = ENV_MUTEX.lock().unwrap();
+            AsyncDatalake::new(
+                Some("username".to_string()),
+                Some("password".to_string()),
+                None,
+                preprod_setting,
+            ).unwrap()
+        };
+        let err =  dtl.refresh_tokens(None).await.err().unwrap();
         let expected_error_message = "Refresh tokens called despite no token set".to_string();
         assert_eq!(err, UnexpectedLibError(DetailedError::new(expected_error_message)));
     }
-}
\ No newline at end of file
+}
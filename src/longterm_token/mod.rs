@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use crate::{ApiError, AsyncDatalake, Datalake, DatalakeError, DetailedError};
+use crate::secret::{redact_json, Secret};
+
+const LONGTERM_TOKENS_PAGE_SIZE: usize = 100;
+
+pub type LongtermTokenId = u64;
+
+/// Long-term API token of the current user, its value is only returned when it is created
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LongtermToken {
+    pub id: LongtermTokenId,
+    pub description: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,  // None if the token never expires
+}
+
+impl LongtermToken {
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Token just created, to be given to [crate::builder::DatalakeBuilder::longterm_token]
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CreatedLongtermToken {
+    #[serde(flatten)]
+    pub info: LongtermToken,
+    pub token: Secret,
+}
+
+#[derive(Deserialize)]
+struct LongtermTokenPage {
+    count: usize,
+    results: Vec<LongtermToken>,
+}
+
+impl Datalake {
+    /// Create a long-term token for the current user, valid until `expires_at` or forever if None
+    pub fn create_longterm_token(&self, description: &str, expires_at: Option<DateTime<Utc>>) -> Result<CreatedLongtermToken, DatalakeError> {
+        self.runtime.block_on(self.inner.create_longterm_token(description, expires_at))
+    }
+
+    /// List every long-term token of the current user, expired ones included
+    pub fn list_longterm_tokens(&self) -> Result<Vec<LongtermToken>, DatalakeError> {
+        self.runtime.block_on(self.inner.list_longterm_tokens())
+    }
+
+    /// Revoke a long-term token, it can't be used to authenticate anymore
+    pub fn revoke_longterm_token(&self, token_id: LongtermTokenId) -> Result<(), DatalakeError> {
+        self.runtime.block_on(self.inner.revoke_longterm_token(token_id))
+    }
+}
+
+impl AsyncDatalake {
+    /// Create a long-term token for the current user, valid until `expires_at` or forever if None
+    pub async fn create_longterm_token(&self, description: &str, expires_at: Option<DateTime<Utc>>) -> Result<CreatedLongtermToken, DatalakeError> {
+        let url = self.settings.routes().longterm_tokens.clone();
+        let request = self.client.post(&url)
+            .header("Accept", "application/json")
+            .json(&json!({"description": description, "expires_at": expires_at}));
+        let resp = self.run_with_authorization_token(request).await?;
+        parse_longterm_token_response(resp, url, "long-term token could not be created").await
+    }
+
+    /// List every long-term token of the current user, expired ones included
+    pub async fn list_longterm_tokens(&self) -> Result<Vec<LongtermToken>, DatalakeError> {
+        let url = self.settings.routes().longterm_tokens.clone();
+        let mut tokens = Vec::new();
+        loop {
+            let request = self.client.get(&url)
+                .header("Accept", "application/json")
+                .query(&[("limit", LONGTERM_TOKENS_PAGE_SIZE), ("offset", tokens.len())]);
+            let resp = self.run_with_authorization_token(request).await?;
+            let page: LongtermTokenPage = parse_longterm_token_response(resp, url.clone(), "long-term tokens could not be listed").await?;
+            let is_last_page = page.results.is_empty() || tokens.len() + page.results.len() >= page.count;
+            tokens.extend(page.results);
+            if is_last_page {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    /// Revoke a long-term token, it can't be used to authenticate anymore
+    pub async fn revoke_longterm_token(&self, token_id: LongtermTokenId) -> Result<(), DatalakeError> {
+        let url = self.settings.routes().longterm_token.replace("{token_id}", &token_id.to_string());
+        let request = self.client.delete(&url)
+            .header("Accept", "application/json");
+        let resp = self.run_with_authorization_token(request).await?;
+        let status_code = resp.status();
+        if resp.error_for_status_ref().is_err() {
+            let err = DetailedError {
+                summary: format!("long-term token {token_id} could not be revoked, error code {status_code}"),
+                api_url: Some(url),
+                api_response: resp.text().await.ok(),
+                api_status_code: Some(status_code),
+            };
+            return Err(ApiError(err));
+        }
+        Ok(())
+    }
+}
+
+/// Parse a response of the token management API, the token values being redacted from errors
+async fn parse_longterm_token_response<T: DeserializeOwned>(resp: Response, url: String, error_summary: &str) -> Result<T, DatalakeError> {
+    let status_code = resp.status();
+    if resp.error_for_status_ref().is_err() {
+        let err = DetailedError {
+            summary: format!("{error_summary}, error code {status_code}"),
+            api_url: Some(url),
+            api_response: resp.text().await.ok(),
+            api_status_code: Some(status_code),
+        };
+        return Err(ApiError(err));
+    }
+    let json_response = resp.json::<Value>().await?;
+    let api_response = Some(redact_json(&json_response));
+    serde_json::from_value::<T>(json_response).map_err(|_| {
+        let summary = "long-term token API response not as expected".to_string();
+        ApiError(DetailedError { summary, api_url: Some(url), api_response, api_status_code: Some(status_code) })
+    })
+}
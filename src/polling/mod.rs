@@ -0,0 +1,143 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use crate::bulk_search::BulkSearchTask;
+
+/// Source of time of the bulk search polling, replaceable by a fake one in tests
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+/// Clock used by default, sleeping on the tokio runtime
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// How long to wait between two polls of a bulk search task
+///
+/// Written in the config like `bulk_search_polling: (strategy: "exponential", multiplier: 2.0, max_interval_sec: 60)`
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum PollingStrategy {
+    /// Always wait bulk_search_retry_interval_sec
+    #[default]
+    Fixed,
+    /// Start with bulk_search_retry_interval_sec, multiplied after each poll up to max_interval_sec
+    Exponential { multiplier: f64, max_interval_sec: u64 },
+    /// Wait until the eta of the task, or longer the further it is in the queue, up to max_interval_sec
+    EtaAware { max_interval_sec: u64 },
+}
+
+impl PollingStrategy {
+    /// Delay before the next poll, given the number of polls already made and the last task polled
+    pub fn delay(&self, interval: Duration, polls: u32, last_task: Option<&BulkSearchTask>, now: DateTime<Utc>) -> Duration {
+        match self {
+            PollingStrategy::Fixed => interval,
+            PollingStrategy::Exponential { multiplier, max_interval_sec } => {
+                let max_interval = Duration::from_secs(*max_interval_sec);
+                let exponent = i32::try_from(polls).unwrap_or(i32::MAX);
+                // Overflows to infinity once far past the cap
+                Duration::try_from_secs_f64(interval.as_secs_f64() * multiplier.powi(exponent))
+                    .unwrap_or(max_interval)
+                    .min(max_interval)
+            }
+            PollingStrategy::EtaAware { max_interval_sec } => {
+                let until_eta = last_task
+                    .and_then(|task| task.eta)
+                    .and_then(|eta| (eta - now).to_std().ok());
+                let queue_position = last_task
+                    .and_then(|task| task.queue_position)
+                    .and_then(|position| u32::try_from(position).ok());
+                let delay = match (until_eta, queue_position) {
+                    (Some(until_eta), _) => until_eta,
+                    (None, Some(position)) => interval.saturating_mul(position.saturating_add(1)),
+                    (None, None) => interval,
+                };
+                delay.max(interval).min(Duration::from_secs(*max_interval_sec))
+            }
+        }
+    }
+}
+
+/// Spread the delay randomly by up to `jitter` (between 0 and 1) of its value, so parallel searches do not poll together
+pub fn with_jitter(delay: Duration, jitter: f64) -> Duration {
+    if jitter <= 0.0 {
+        return delay;
+    }
+    let factor = 1.0 + jitter.min(1.0) * (2.0 * fastrand::f64() - 1.0);
+    delay.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use crate::bulk_search::BulkSearchTask;
+    use crate::polling::{with_jitter, PollingStrategy};
+
+    fn task(eta: Option<&str>, queue_position: Option<i64>) -> BulkSearchTask {
+        serde_json::from_value(json!({
+            "uuid": "task_uuid123",
+            "state": "QUEUED",
+            "created_at": "2022-08-22T07:11:32Z",
+            "eta": eta,
+            "queue_position": queue_position,
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_exponential_delay_is_capped() {
+        let strategy = PollingStrategy::Exponential { multiplier: 2.0, max_interval_sec: 30 };
+        let now = Utc::now();
+
+        let delays: Vec<u64> = (0..6)
+            .map(|polls| strategy.delay(Duration::from_secs(5), polls, None, now).as_secs())
+            .collect();
+
+        assert_eq!(delays, [5, 10, 20, 30, 30, 30]);
+        assert_eq!(strategy.delay(Duration::from_secs(5), u32::MAX, None, now), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_eta_aware_delay() {
+        let strategy = PollingStrategy::EtaAware { max_interval_sec: 120 };
+        let interval = Duration::from_secs(10);
+        let now = Utc.with_ymd_and_hms(2022, 8, 22, 7, 12, 0).unwrap();
+
+        let eta_in_45_sec = task(Some("2022-08-22T07:12:45Z"), None);
+        assert_eq!(strategy.delay(interval, 1, Some(&eta_in_45_sec), now), Duration::from_secs(45));
+        let eta_passed = task(Some("2022-08-22T07:11:00Z"), None);
+        assert_eq!(strategy.delay(interval, 1, Some(&eta_passed), now), interval);
+        let far_in_queue = task(None, Some(50));
+        assert_eq!(strategy.delay(interval, 1, Some(&far_in_queue), now), Duration::from_secs(120));
+        let second_in_queue = task(None, Some(2));
+        assert_eq!(strategy.delay(interval, 1, Some(&second_in_queue), now), Duration::from_secs(30));
+        assert_eq!(strategy.delay(interval, 0, None, now), interval);
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let delay = Duration::from_secs(10);
+
+        assert_eq!(with_jitter(delay, 0.0), delay);
+        for _ in 0..100 {
+            let jittered = with_jitter(delay, 0.2);
+            assert!(jittered >= Duration::from_secs(8) && jittered <= Duration::from_secs(12), "{jittered:?}");
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use crate::{DatalakeError, DetailedError};
+use crate::advanced_search::AdvancedQuery;
+use crate::atom::AtomType;
+use crate::threat::ThreatType;
+use crate::DatalakeError::ParseError;
+
+const FILTER_TYPE: &str = "filter";
+
+/// Advanced search query body, an AND/OR tree of filters
+///
+/// ```
+/// use chrono::Duration;
+/// use ocd_datalake_rs::atom::AtomType;
+/// use ocd_datalake_rs::query::{Filter, Query};
+/// use ocd_datalake_rs::threat::ThreatType;
+///
+/// let query = Query::and([
+///     Filter::atom_type(AtomType::Ip),
+///     Filter::tag("ransomware"),
+///     Filter::score_gte(ThreatType::Malware, 60),
+///     Filter::last_updated_within(Duration::days(1)),
+/// ]);
+/// ```
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Query {
+    #[serde(rename = "AND")]
+    And(Vec<QueryNode>),
+    #[serde(rename = "OR")]
+    Or(Vec<QueryNode>),
+}
+
+/// Element of a [Query]: either a nested group or a single filter
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum QueryNode {
+    Group(Query),
+    Filter(Filter),
+}
+
+/// Filter on a single field, fields not modelled are kept in `extra`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Filter {
+    pub field: String,
+    #[serde(rename = "type", default = "filter_type_default")]
+    pub filter_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multi_values: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Map<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inner_params: Option<Map<String, Value>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+fn filter_type_default() -> String {
+    FILTER_TYPE.to_string()
+}
+
+impl Query {
+    /// Match threats matching all the nodes
+    pub fn and<I>(nodes: I) -> Self where I: IntoIterator, I::Item: Into<QueryNode> {
+        Query::And(nodes.into_iter().map(Into::into).collect())
+    }
+
+    /// Match threats matching at least one of the nodes
+    pub fn or<I>(nodes: I) -> Self where I: IntoIterator, I::Item: Into<QueryNode> {
+        Query::Or(nodes.into_iter().map(Into::into).collect())
+    }
+
+    /// JSON query body, as accepted by the API
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).expect("a query is always serializable to JSON")
+    }
+
+    /// Parse an existing JSON query body, like the ones saved from the Datalake GUI
+    pub fn from_value(query_body: &Value) -> Result<Self, DatalakeError> {
+        Query::deserialize(query_body).map_err(|err| {
+            ParseError(DetailedError::new(format!("query body not as expected: {err}")))
+        })
+    }
+}
+
+impl Filter {
+    /// Empty filter on a field, to be completed for filters without a dedicated constructor
+    pub fn new(field: &str) -> Self {
+        Filter {
+            field: field.to_string(),
+            filter_type: filter_type_default(),
+            value: None,
+            multi_values: None,
+            range: None,
+            inner_params: None,
+            extra: Map::new(),
+        }
+    }
+
+    pub fn atom_type(atom_type: AtomType) -> Self {
+        Self::atom_types([atom_type])
+    }
+
+    /// Match threats of any of the given atom types
+    pub fn atom_types<I: IntoIterator<Item = AtomType>>(atom_types: I) -> Self {
+        let mut filter = Self::new("atom_type");
+        filter.multi_values = Some(atom_types.into_iter().map(|atom_type| json!(atom_type)).collect());
+        filter
+    }
+
+    pub fn tag(tag: &str) -> Self {
+        Self::tags([tag])
+    }
+
+    /// Match threats having any of the given tags
+    pub fn tags<'a, I: IntoIterator<Item = &'a str>>(tags: I) -> Self {
+        let mut filter = Self::new("tags");
+        filter.multi_values = Some(tags.into_iter().map(|tag| json!(tag)).collect());
+        filter
+    }
+
+    /// Match threats with a risk score greater or equal to `score` for the threat type
+    pub fn score_gte(threat_type: ThreatType, score: u8) -> Self {
+        Self::score_range(threat_type, "gte", score)
+    }
+
+    /// Match threats with a risk score lower or equal to `score` for the threat type
+    pub fn score_lte(threat_type: ThreatType, score: u8) -> Self {
+        Self::score_range(threat_type, "lte", score)
+    }
+
+    /// Match threats updated less than `duration` ago
+    pub fn last_updated_within(duration: chrono::Duration) -> Self {
+        Self::within("last_updated", duration)
+    }
+
+    /// Match threats first seen less than `duration` ago
+    pub fn first_seen_within(duration: chrono::Duration) -> Self {
+        Self::within("first_seen", duration)
+    }
+
+    fn score_range(threat_type: ThreatType, operator: &str, score: u8) -> Self {
+        let mut filter = Self::new("risk");
+        filter.inner_params = Some(Map::from_iter([("threat_types".to_string(), json!([threat_type]))]));
+        filter.range = Some(Map::from_iter([(operator.to_string(), json!(score))]));
+        filter
+    }
+
+    fn within(field: &str, duration: chrono::Duration) -> Self {
+        let mut filter = Self::new(field);
+        filter.value = Some(json!(duration.num_seconds()));  // API expects a number of seconds
+        filter
+    }
+}
+
+impl From<Query> for QueryNode {
+    fn from(query: Query) -> Self {
+        QueryNode::Group(query)
+    }
+}
+
+impl From<Filter> for QueryNode {
+    fn from(filter: Filter) -> Self {
+        QueryNode::Filter(filter)
+    }
+}
+
+impl From<Query> for Value {
+    fn from(query: Query) -> Self {
+        query.to_value()
+    }
+}
+
+impl From<Query> for AdvancedQuery {
+    fn from(query: Query) -> Self {
+        AdvancedQuery::Body(query.to_value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use serde_json::json;
+    use crate::atom::AtomType;
+    use crate::query::{Filter, Query};
+    use crate::threat::ThreatType;
+
+    #[test]
+    fn test_query_serialization() {
+        let query = Query::and([
+            Filter::atom_type(AtomType::Ip),
+            Filter::tag("ransomware"),
+            Filter::score_gte(ThreatType::Malware, 60),
+            Filter::last_updated_within(Duration::days(1)),
+        ]);
+
+        let expected_body = json!({"AND": [
+            {"field": "atom_type", "type": "filter", "multi_values": ["ip"]},
+            {"field": "tags", "type": "filter", "multi_values": ["ransomware"]},
+            {"field": "risk", "type": "filter", "inner_params": {"threat_types": ["malware"]}, "range": {"gte": 60}},
+            {"field": "last_updated", "type": "filter", "value": 86400},
+        ]});
+        assert_eq!(query.to_value(), expected_body);
+    }
+
+    #[test]
+    fn test_nested_query_serialization() {
+        let query = Query::or([
+            Query::and([Filter::atom_types([AtomType::Domain, AtomType::Fqdn])]),
+            Query::and([Filter::score_lte(ThreatType::Phishing, 20)]),
+        ]);
+
+        let expected_body = json!({"OR": [
+            {"AND": [{"field": "atom_type", "type": "filter", "multi_values": ["domain", "fqdn"]}]},
+            {"AND": [{"field": "risk", "type": "filter", "inner_params": {"threat_types": ["phishing"]}, "range": {"lte": 20}}]},
+        ]});
+        assert_eq!(query.to_value(), expected_body);
+    }
+
+    #[test]
+    fn test_query_round_trip() {
+        let query_body = json!({"AND": [{"AND": [
+            {"field": "atom_type", "multi_values": ["ip", "url"], "type": "filter"},
+            {"field": "sources", "inner_params": {"sources": ["src_1"]}, "type": "filter", "value": "*"},
+            {"OR": [{"field": "first_seen", "type": "filter", "value": 3600, "custom_key": true}]},
+        ]}]});
+
+        let query = Query::from_value(&query_body).unwrap();
+
+        assert_eq!(query.to_value(), query_body);
+    }
+
+    #[test]
+    fn test_invalid_query_body() {
+        let err = Query::from_value(&json!({"NOT": []})).unwrap_err();
+
+        assert!(err.to_string().starts_with("Parse Error query body not as expected"));
+    }
+}
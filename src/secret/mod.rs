@@ -0,0 +1,100 @@
+use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use zeroize::Zeroizing;
+
+pub(crate) const REDACTED: &str = "[REDACTED]";
+/// Keys of the authentication endpoints bodies holding a credential
+const SECRET_KEYS: [&str; 4] = ["access_token", "refresh_token", "password", "token"];
+
+/// Credential hidden from `Debug` and wiped from memory when dropped
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(secret: String) -> Self {
+        Secret(Zeroizing::new(secret))
+    }
+
+    /// Cleartext value, to be sent to the API only
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret::new(secret)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Secret::new(secret.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.expose())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+/// Response of an authentication endpoint with its credentials redacted, to be kept in errors
+pub(crate) fn redact_json(value: &Value) -> String {
+    fn redact(value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if SECRET_KEYS.contains(&key.as_str()) {
+                        *value = Value::String(REDACTED.to_string());
+                    } else {
+                        redact(value);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(redact),
+            _ => {}
+        }
+    }
+    let mut redacted = value.clone();
+    redact(&mut redacted);
+    redacted.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::secret::{redact_json, Secret};
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let secret = Secret::from("my_password");
+
+        assert_eq!(format!("{secret:?}"), "[REDACTED]");
+        assert_eq!(format!("{:?}", Some(secret.clone())), "Some([REDACTED])");
+        assert_eq!(secret.expose(), "my_password");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), r#""my_password""#);
+    }
+
+    #[test]
+    fn test_redact_json() {
+        let response = json!({"access_token": "123", "messages": [{"refresh_token": "456"}], "detail": "Invalid credentials"});
+
+        let redacted = redact_json(&response);
+
+        assert_eq!(redacted, r#"{"access_token":"[REDACTED]","detail":"Invalid credentials","messages":[{"refresh_token":"[REDACTED]"}]}"#);
+    }
+}
@@ -1,19 +1,125 @@
 const CONFIG_ENV_PREFIX: &str = "OCD_DTL_RS";
+const CONFIG_FILE_ENV_VARIABLE: &str = "OCD_DTL_RS_CONFIG";
 const PREPROD_BASE_URL: &str = "https://ti2.extranet.mrti-center.com/api/v3";
 
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
 use config::FileFormat;
+use reqwest::Url;
 use serde::Deserialize;
+use crate::DatalakeError;
+use crate::DatalakeError::ConfigError;
+use crate::error::DetailedConfigError;
+use crate::polling::PollingStrategy;
+use crate::secret::Secret;
 
 
+// Routes added after the first config format, defaulted so older configs still load
+fn default_threat_lookup_route() -> String {
+    "{base_url}/mrti/threats/lookup/".to_string()
+}
+
+fn default_threat_route() -> String {
+    "{base_url}/mrti/threats/{hashkey}/".to_string()
+}
+
+fn default_bulk_search_cancel_route() -> String {
+    "{base_url}/mrti/bulk-search/task/{task_uuid}/cancel/".to_string()
+}
+
+fn default_advanced_search_route() -> String {
+    "{base_url}/mrti/advanced-queries/threats/".to_string()
+}
+
+fn default_advanced_search_hash_route() -> String {
+    "{base_url}/mrti/advanced-queries/threats/{query_hash}/".to_string()
+}
+
+fn default_longterm_tokens_route() -> String {
+    "{base_url}/auth/long-term-tokens/".to_string()
+}
+
+fn default_longterm_token_route() -> String {
+    "{base_url}/auth/long-term-tokens/{token_id}/".to_string()
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct RoutesSetting {
     pub authentication: String,
     pub refresh_token: String,
     pub atom_values_extract: String,
+    #[serde(default = "default_threat_lookup_route")]
+    pub threat_lookup: String,
+    #[serde(default = "default_threat_route")]
+    pub threat: String,
     pub bulk_lookup: String,
     pub bulk_search: String,
     pub bulk_search_task: String,
     pub bulk_search_download: String,
+    #[serde(default = "default_bulk_search_cancel_route")]
+    pub bulk_search_cancel: String,
+    #[serde(default = "default_advanced_search_route")]
+    pub advanced_search: String,
+    #[serde(default = "default_advanced_search_hash_route")]
+    pub advanced_search_hash: String,
+    #[serde(default = "default_longterm_tokens_route")]
+    pub longterm_tokens: String,
+    #[serde(default = "default_longterm_token_route")]
+    pub longterm_token: String,
+}
+
+impl RoutesSetting {
+    /// Routes with their key in the config
+    fn named_routes(&self) -> [(&'static str, &String); 14] {
+        [
+            ("authentication", &self.authentication),
+            ("refresh_token", &self.refresh_token),
+            ("atom_values_extract", &self.atom_values_extract),
+            ("threat_lookup", &self.threat_lookup),
+            ("threat", &self.threat),
+            ("bulk_lookup", &self.bulk_lookup),
+            ("bulk_search", &self.bulk_search),
+            ("bulk_search_task", &self.bulk_search_task),
+            ("bulk_search_download", &self.bulk_search_download),
+            ("bulk_search_cancel", &self.bulk_search_cancel),
+            ("advanced_search", &self.advanced_search),
+            ("advanced_search_hash", &self.advanced_search_hash),
+            ("longterm_tokens", &self.longterm_tokens),
+            ("longterm_token", &self.longterm_token),
+        ]
+    }
+}
+
+/// Proxy used by every request of a Datalake instance
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProxySetting {
+    /// http(s):// or socks5(h):// url of the proxy
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    /// Hosts that bypass the proxy, comma separated like the NO_PROXY env variable
+    pub no_proxy: Option<String>,
+}
+
+impl ProxySetting {
+    pub fn new(url: String) -> Self {
+        ProxySetting {
+            url,
+            username: None,
+            password: None,
+            no_proxy: None,
+        }
+    }
+}
+
+fn default_bulk_lookup_parallelism() -> usize {
+    1
+}
+
+fn default_token_refresh_skew_sec() -> u64 {
+    60
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -21,11 +127,22 @@ pub struct DatalakeSetting {
     base_url: String,
     routes: RoutesSetting,
     // raw routes with {base_url} in them
-    formatted_routes: Option<RoutesSetting>,  // final routes, only set after replace_base_url is called
+    #[serde(skip)]
+    formatted_routes: OnceLock<RoutesSetting>,  // final routes, formatted on first use so hand deserialized settings get them too
     // Other settings
     pub bulk_lookup_chunk_size: usize,
+    #[serde(default = "default_bulk_lookup_parallelism")]
+    pub bulk_lookup_parallelism: usize,  // Number of chunks looked up at the same time
     pub bulk_search_retry_interval_sec: u64,
     pub bulk_search_timeout_sec: u64,
+    #[serde(default)]
+    pub bulk_search_polling: PollingStrategy,
+    #[serde(default)]
+    pub bulk_search_polling_jitter: f64,  // Fraction of the polling delay randomly added or removed, between 0 and 1
+    #[serde(default = "default_token_refresh_skew_sec")]
+    pub token_refresh_skew_sec: u64,  // Access tokens are refreshed this long before they expire, at most halfway through their lifetime
+    // Falls back on the OCD_DTL_RS_HTTP_PROXY, then HTTPS_PROXY and HTTP_PROXY env variables if not set
+    pub proxy: Option<ProxySetting>,
 }
 
 impl DatalakeSetting {
@@ -35,39 +152,161 @@ impl DatalakeSetting {
 
     pub fn set_base_url(&mut self, base_url: String) {
         self.base_url = base_url;
-        self.replace_base_url()
+        self.formatted_routes = OnceLock::new();
     }
 
-    fn replace_base_url(&mut self) {
-        self.formatted_routes = Some(RoutesSetting {
+    fn format_routes(&self) -> RoutesSetting {
+        RoutesSetting {
             authentication: self.routes.authentication.replace("{base_url}", &self.base_url),
             refresh_token: self.routes.refresh_token.replace("{base_url}", &self.base_url),
             atom_values_extract: self.routes.atom_values_extract.replace("{base_url}", &self.base_url),
+            threat_lookup: self.routes.threat_lookup.replace("{base_url}", &self.base_url),
+            threat: self.routes.threat.replace("{base_url}", &self.base_url),
             bulk_lookup: self.routes.bulk_lookup.replace("{base_url}", &self.base_url),
             bulk_search: self.routes.bulk_search.replace("{base_url}", &self.base_url),
             bulk_search_task: self.routes.bulk_search_task.replace("{base_url}", &self.base_url),
             bulk_search_download: self.routes.bulk_search_download.replace("{base_url}", &self.base_url),
-        })
+            bulk_search_cancel: self.routes.bulk_search_cancel.replace("{base_url}", &self.base_url),
+            advanced_search: self.routes.advanced_search.replace("{base_url}", &self.base_url),
+            advanced_search_hash: self.routes.advanced_search_hash.replace("{base_url}", &self.base_url),
+            longterm_tokens: self.routes.longterm_tokens.replace("{base_url}", &self.base_url),
+            longterm_token: self.routes.longterm_token.replace("{base_url}", &self.base_url),
+        }
     }
 
+    /// Parse a RON config, see conf/conf.prod.ron for the expected format
+    ///
+    /// > **Warning** panics on an invalid config, use [DatalakeSetting::try_new] to handle the error
     pub fn new(config: &str) -> DatalakeSetting {
+        match Self::try_new(config) {
+            Ok(settings) => settings,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Parse and validate a RON config, see conf/conf.prod.ron for the expected format
+    pub fn try_new(config: &str) -> Result<DatalakeSetting, DatalakeError> {
         let builder = config::Config::builder()
             .add_source(config::File::from_str(config, FileFormat::Ron))
             .add_source(config::Environment::with_prefix(CONFIG_ENV_PREFIX));
-        let some_config = match builder.build() {
-            Ok(valid_config) => valid_config,
-            Err(e) => panic!("Config parse error: {:?}", e),
+        let some_config = builder.build()
+            .map_err(|e| Self::config_error("Config parse error", e))?;
+        let raw_settings = some_config.get::<config::Value>("datalake_setting")
+            .map_err(|e| Self::config_error("Config is not as expected", e))?;
+        // Deserialized with the path of the fields to point at the offending key on error
+        let mut settings: DatalakeSetting = serde_path_to_error::deserialize(raw_settings)
+            .map_err(|e| Self::deserialize_error(e, config))?;
+        if settings.proxy.is_none() {
+            // Set with the OCD_DTL_RS_HTTP_PROXY env variable
+            settings.proxy = some_config.get_string("http_proxy").ok().map(ProxySetting::new);
+        }
+        settings.validate(config)?;
+        Ok(settings)
+    }
+
+    /// Parse and validate a RON config file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<DatalakeSetting, DatalakeError> {
+        let path = path.as_ref();
+        let config = fs::read_to_string(path).map_err(|e| {
+            ConfigError(DetailedConfigError::new(format!("Could not read config file {} : {e}", path.display())))
+        })?;
+        Self::try_new(&config)
+    }
+
+    /// Parse the config file given by the OCD_DTL_RS_CONFIG env variable, or the prod config if it is unset
+    pub fn from_env() -> Result<DatalakeSetting, DatalakeError> {
+        match env::var(CONFIG_FILE_ENV_VARIABLE) {
+            Ok(path) => Self::from_file(path),
+            Err(_) => Self::try_new(include_str!("../../conf/conf.prod.ron")),
+        }
+    }
+
+    fn validate(&self, config: &str) -> Result<(), DatalakeError> {
+        if self.bulk_lookup_chunk_size == 0 {
+            let key = "bulk_lookup_chunk_size";
+            return Err(ConfigError(DetailedConfigError {
+                summary: "bulk_lookup_chunk_size must be greater than 0".to_string(),
+                key: Some(key.to_string()),
+                line: Self::line_of(config, key),
+            }));
+        }
+        if self.bulk_lookup_parallelism == 0 {
+            let key = "bulk_lookup_parallelism";
+            return Err(ConfigError(DetailedConfigError {
+                summary: "bulk_lookup_parallelism must be greater than 0".to_string(),
+                key: Some(key.to_string()),
+                line: Self::line_of(config, key),
+            }));
+        }
+        if !(0.0..=1.0).contains(&self.bulk_search_polling_jitter) {
+            let key = "bulk_search_polling_jitter";
+            return Err(ConfigError(DetailedConfigError {
+                summary: "bulk_search_polling_jitter must be between 0 and 1".to_string(),
+                key: Some(key.to_string()),
+                line: Self::line_of(config, key),
+            }));
+        }
+        if let PollingStrategy::Exponential { multiplier, .. } = self.bulk_search_polling {
+            if multiplier.is_nan() || multiplier < 1.0 {
+                let key = "bulk_search_polling";
+                return Err(ConfigError(DetailedConfigError {
+                    summary: "bulk_search_polling multiplier must be at least 1".to_string(),
+                    key: Some(key.to_string()),
+                    line: Self::line_of(config, key),
+                }));
+            }
+        }
+        for (key, route) in self.routes().named_routes() {
+            if let Err(e) = Url::parse(route) {
+                return Err(ConfigError(DetailedConfigError {
+                    summary: format!("Route {route} is not a valid url : {e}"),
+                    key: Some(format!("routes.{key}")),
+                    line: Self::line_of(config, key),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn config_error(summary: &str, error: config::ConfigError) -> DatalakeError {
+        let (key, line) = match &error {
+            // RON errors start with the line and column of the error, like "1:5: Non-whitespace trailing characters"
+            config::ConfigError::FileParse { cause, .. } => (None, cause.to_string().split(':').next().and_then(|line| line.parse().ok())),
+            config::ConfigError::NotFound(key) => (Some(key.clone()), None),
+            _ => (None, None),
+        };
+        ConfigError(DetailedConfigError { summary: format!("{summary}: {error}"), key, line })
+    }
+
+    fn deserialize_error(error: serde_path_to_error::Error<config::ConfigError>, config: &str) -> DatalakeError {
+        let path = error.path().to_string();  // "." for the root
+        let inner = error.into_inner();
+        let message = inner.to_string();
+        // Missing fields are reported on their parent as "missing field `field_name`"
+        let missing_field = message.strip_prefix("missing field `").and_then(|field| field.split('`').next());
+        let key = match missing_field {
+            Some(field) if path == "." => field.to_string(),
+            Some(field) => format!("{path}.{field}"),
+            None => path,
         };
-        let mut settings = match some_config.get::<DatalakeSetting>("datalake_setting") {
-            Ok(valid_settings) => { valid_settings }
-            Err(e) => { panic!("Config is not as expected: {:?}", e) }
+        let line = match missing_field {
+            Some(_) => None,
+            None => Self::line_of(config, key.rsplit('.').next().unwrap_or(&key)),
         };
-        settings.replace_base_url();
-        settings
+        ConfigError(DetailedConfigError { summary: format!("Config is not as expected: {message}"), key: Some(key), line })
     }
 
+    /// First line (starting at 1) defining the given field in the config
+    fn line_of(config: &str, field: &str) -> Option<usize> {
+        let field_definition = format!("{field}:");
+        config.lines()
+            .position(|line| line.trim_start().starts_with(&field_definition))
+            .map(|index| index + 1)
+    }
+
+    /// Routes with {base_url} replaced
     pub fn routes(&self) -> &RoutesSetting {
-        self.formatted_routes.as_ref().expect("Formatted routes could not be found")
+        self.formatted_routes.get_or_init(|| self.format_routes())
     }
 
     #[allow(dead_code)]
@@ -86,7 +325,15 @@ impl DatalakeSetting {
 
 #[cfg(test)]
 mod tests {
+    use std::env;
+    use rstest::rstest;
     use crate::DatalakeSetting;
+    use crate::error::DatalakeError::ConfigError;
+    use crate::error::DetailedConfigError;
+    use crate::polling::PollingStrategy;
+    use crate::setting::ProxySetting;
+
+    const PROD_CONFIG: &str = include_str!("../../conf/conf.prod.ron");
 
 
     #[test]
@@ -102,9 +349,165 @@ mod tests {
         assert_eq!(preprod_setting.base_url, "https://ti2.extranet.mrti-center.com/api/v3");
     }
 
+    #[test]
+    fn test_proxy_config() {
+        let config = include_str!("../../conf/conf.prod.ron").replace(
+            "bulk_search_timeout_sec: 3600,",
+            r#"bulk_search_timeout_sec: 3600, proxy: Some(ProxySetting(url: "socks5://proxy.local:1080", username: Some("user"), password: Some("pass"))),"#,
+        );
+        let setting = DatalakeSetting::new(&config);
+        assert_eq!(setting.proxy, Some(ProxySetting {
+            url: "socks5://proxy.local:1080".to_string(),
+            username: Some("user".to_string()),
+            password: Some("pass".into()),
+            no_proxy: None,
+        }));
+    }
+
+    #[test]
+    fn test_proxy_from_env() {
+        let config = include_str!("../../conf/conf.prod.ron");
+        let _mutex = crate::tests::ENV_MUTEX.lock().unwrap();
+        env::set_var("OCD_DTL_RS_HTTP_PROXY", "http://env-proxy.local:3128");
+        let setting = DatalakeSetting::new(config);
+        env::remove_var("OCD_DTL_RS_HTTP_PROXY");
+        assert_eq!(setting.proxy, Some(ProxySetting::new("http://env-proxy.local:3128".to_string())));
+    }
+
     #[test]
     #[should_panic(expected = "Config parse error: 1:5: Non-whitespace trailing characters")]
     fn test_invalid_config() {
         DatalakeSetting::new("not a correct config");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_try_new_parse_error() {
+        let err = DatalakeSetting::try_new("not a correct config").err().unwrap();
+        assert_eq!(err, ConfigError(DetailedConfigError {
+            summary: "Config parse error: 1:5: Non-whitespace trailing characters".to_string(),
+            key: None,
+            line: Some(1),
+        }));
+    }
+
+    #[test]
+    fn test_try_new_type_error() {
+        let config = PROD_CONFIG.replace("bulk_lookup_chunk_size: 100", r#"bulk_lookup_chunk_size: "hundred""#);
+        let err = DatalakeSetting::try_new(&config).err().unwrap();
+        let ConfigError(detailed_err) = err else { panic!("Unexpected error {err:?}") };
+        assert_eq!(detailed_err.key, Some("bulk_lookup_chunk_size".to_string()));
+        assert_eq!(detailed_err.line, Some(20));
+    }
+
+    #[test]
+    fn test_try_new_missing_field() {
+        let config = PROD_CONFIG.replace(r#"bulk_search_task: "{base_url}/mrti/bulk-search/tasks/","#, "");
+        let err = DatalakeSetting::try_new(&config).err().unwrap();
+        let ConfigError(detailed_err) = err else { panic!("Unexpected error {err:?}") };
+        assert_eq!(detailed_err.key, Some("routes.bulk_search_task".to_string()));
+        assert_eq!(detailed_err.summary, "Config is not as expected: missing field `bulk_search_task`");
+    }
+
+    #[rstest]
+    #[case("bulk_lookup_chunk_size: 100", "bulk_lookup_chunk_size: 0", "bulk_lookup_chunk_size", 20)]
+    #[case("bulk_lookup_chunk_size: 100,", "bulk_lookup_chunk_size: 100,\nbulk_lookup_parallelism: 0,", "bulk_lookup_parallelism", 21)]
+    #[case(r#"bulk_lookup: "{base_url}"#, r#"bulk_lookup: "not an url"#, "routes.bulk_lookup", 10)]
+    #[case("https://datalake.cert.orangecyberdefense.com/api/v3", "datalake.cert.orangecyberdefense.com", "routes.authentication", 5)]
+    fn test_try_new_validation(#[case] from: &str, #[case] to: &str, #[case] expected_key: &str, #[case] expected_line: usize) {
+        let config = PROD_CONFIG.replace(from, to);
+        let err = DatalakeSetting::try_new(&config).err().unwrap();
+        let ConfigError(detailed_err) = err else { panic!("Unexpected error {err:?}") };
+        assert_eq!(detailed_err.key, Some(expected_key.to_string()));
+        assert_eq!(detailed_err.line, Some(expected_line));
+    }
+
+    #[test]
+    fn test_polling_config() {
+        let config = PROD_CONFIG.replace(
+            "bulk_search_timeout_sec: 3600,",
+            r#"bulk_search_timeout_sec: 3600, bulk_search_polling: (strategy: "exponential", multiplier: 1.5, max_interval_sec: 60), bulk_search_polling_jitter: 0.1,"#,
+        );
+        let setting = DatalakeSetting::new(&config);
+        assert_eq!(setting.bulk_search_polling, PollingStrategy::Exponential { multiplier: 1.5, max_interval_sec: 60 });
+        assert_eq!(setting.bulk_search_polling_jitter, 0.1);
+        assert_eq!(DatalakeSetting::prod().bulk_search_polling, PollingStrategy::Fixed);
+    }
+
+    #[rstest]
+    #[case(r#"bulk_search_polling: (strategy: "exponential", multiplier: 0.5, max_interval_sec: 60),"#, "bulk_search_polling")]
+    #[case("bulk_search_polling_jitter: 1.5,", "bulk_search_polling_jitter")]
+    fn test_invalid_polling_config(#[case] polling_config: &str, #[case] expected_key: &str) {
+        let config = PROD_CONFIG.replace("bulk_search_timeout_sec: 3600,", &format!("bulk_search_timeout_sec: 3600,\n{polling_config}"));
+        let err = DatalakeSetting::try_new(&config).err().unwrap();
+        let ConfigError(detailed_err) = err else { panic!("Unexpected error {err:?}") };
+        assert_eq!(detailed_err.key, Some(expected_key.to_string()));
+        assert_eq!(detailed_err.line, Some(23));
+    }
+
+    #[test]
+    fn test_from_file() {
+        let setting = DatalakeSetting::from_file("examples/custom_config.ron").unwrap();
+        assert_eq!(setting.routes().authentication, "https://custom_host/auth/token/");
+
+        let err = DatalakeSetting::from_file("examples/missing_config.ron").err().unwrap();
+        assert!(err.to_string().starts_with("Config Error Could not read config file examples/missing_config.ron"));
+    }
+
+    #[test]
+    fn test_routes_of_a_hand_deserialized_setting() {
+        let setting: DatalakeSetting = serde_json::from_value(serde_json::json!({
+            "base_url": "https://custom_host",
+            "routes": {
+                "authentication": "{base_url}/auth/token/",
+                "refresh_token": "{base_url}/auth/refresh-token/",
+                "atom_values_extract": "{base_url}/mrti/threats/atom-values-extract/",
+                "bulk_lookup": "{base_url}/mrti/threats/bulk-lookup/",
+                "bulk_search": "{base_url}/mrti/bulk-search/",
+                "bulk_search_task": "{base_url}/mrti/bulk-search/tasks/",
+                "bulk_search_download": "{base_url}/mrti/bulk-search/task/{task_uuid}",
+            },
+            "bulk_lookup_chunk_size": 100,
+            "bulk_search_retry_interval_sec": 10,
+            "bulk_search_timeout_sec": 3600,
+        })).unwrap();  // Without try_new
+
+        assert_eq!(setting.routes().authentication, "https://custom_host/auth/token/");
+        let mut setting = setting.clone();
+        setting.set_base_url("https://other_host".to_string());
+        assert_eq!(setting.routes().authentication, "https://other_host/auth/token/");
+    }
+
+    #[test]
+    fn test_config_without_the_newer_routes() {
+        // Config format of the first releases, before the threat, cancel, advanced search and long-term token routes
+        let config = r#"(
+            datalake_setting: DatalakeSetting(
+                base_url: "https://custom_host",
+                routes: RoutesSetting(
+                    authentication: "{base_url}/auth/token/",
+                    refresh_token: "{base_url}/auth/refresh-token/",
+                    atom_values_extract: "{base_url}/mrti/threats/atom-values-extract/",
+                    bulk_lookup: "{base_url}/mrti/threats/bulk-lookup/",
+                    bulk_search: "{base_url}/mrti/bulk-search/",
+                    bulk_search_task: "{base_url}/mrti/bulk-search/tasks/",
+                    bulk_search_download: "{base_url}/mrti/bulk-search/task/{task_uuid}",
+                ),
+                bulk_lookup_chunk_size: 100,
+                bulk_search_retry_interval_sec: 10,
+                bulk_search_timeout_sec: 3600,
+            )
+        )"#;
+
+        let setting = DatalakeSetting::try_new(config).unwrap();
+
+        let routes = setting.routes();
+        assert_eq!(routes.authentication, "https://custom_host/auth/token/");
+        assert_eq!(routes.threat_lookup, "https://custom_host/mrti/threats/lookup/");
+        assert_eq!(routes.threat, "https://custom_host/mrti/threats/{hashkey}/");
+        assert_eq!(routes.bulk_search_cancel, "https://custom_host/mrti/bulk-search/task/{task_uuid}/cancel/");
+        assert_eq!(routes.advanced_search, "https://custom_host/mrti/advanced-queries/threats/");
+        assert_eq!(routes.advanced_search_hash, "https://custom_host/mrti/advanced-queries/threats/{query_hash}/");
+        assert_eq!(routes.longterm_tokens, "https://custom_host/auth/long-term-tokens/");
+        assert_eq!(routes.longterm_token, "https://custom_host/auth/long-term-tokens/{token_id}/");
+    }
+}
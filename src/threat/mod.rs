@@ -0,0 +1,134 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use strum_macros::{Display, EnumString};
+use crate::atom::AtomType;
+use crate::{ApiError, AsyncDatalake, Datalake, DatalakeError, DetailedError};
+
+/// Category of a threat, each one having its own score
+#[derive(Serialize, Deserialize, Debug, Display, EnumString, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ThreatType {
+    Ddos,
+    Fraud,
+    Hack,
+    Leak,
+    Malware,
+    Phishing,
+    Scam,
+    Scan,
+    Spam,
+}
+
+/// Score given to a threat for one threat type
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ThreatScore {
+    pub threat_type: ThreatType,
+    pub score: Score,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Score {
+    pub risk: u8,
+    pub reliability: Option<u8>,
+}
+
+/// Source that reported a threat
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ThreatSource {
+    pub source_id: String,
+    pub count: Option<u64>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_updated: Option<DateTime<Utc>>,
+    pub max_score: Option<u8>,
+    pub min_score: Option<u8>,
+}
+
+/// Threat as returned by the API, fields not modelled are kept in `extra`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Threat {
+    pub hashkey: String,
+    pub atom_type: Option<AtomType>,
+    pub atom_value: Option<String>,
+    #[serde(default = "threat_found_default")]
+    pub threat_found: bool,
+    #[serde(default)]
+    pub scores: Vec<ThreatScore>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub sources: Vec<ThreatSource>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_updated: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Only the lookup endpoint returns the threat_found field, the threat endpoint returns 404 for unknown threats
+fn threat_found_default() -> bool {
+    true
+}
+
+impl Threat {
+    /// Risk score for a given threat type (malware, phishing, ...)
+    pub fn score(&self, threat_type: ThreatType) -> Option<u8> {
+        self.scores.iter()
+            .find(|threat_score| threat_score.threat_type == threat_type)
+            .map(|threat_score| threat_score.score.risk)
+    }
+}
+
+impl Datalake {
+    /// Lookup a single atom and return the full threat, `threat_found` is false if the atom is unknown
+    pub fn lookup(&self, atom_value: &str, atom_type: AtomType) -> Result<Threat, DatalakeError> {
+        self.runtime.block_on(self.inner.lookup(atom_value, atom_type))
+    }
+
+    /// Retrieve a threat from its hashkey
+    pub fn get_threat(&self, hashkey: &str) -> Result<Threat, DatalakeError> {
+        self.runtime.block_on(self.inner.get_threat(hashkey))
+    }
+}
+
+impl AsyncDatalake {
+    /// Lookup a single atom and return the full threat, `threat_found` is false if the atom is unknown
+    pub async fn lookup(&self, atom_value: &str, atom_type: AtomType) -> Result<Threat, DatalakeError> {
+        let url = self.settings.routes().threat_lookup.clone();
+        let request = self.client.get(&url)
+            .header("Accept", "application/json")
+            .query(&[("atom_value", atom_value), ("atom_type", &atom_type.to_string()), ("hashkey_only", "false")]);
+        self.fetch_threat(request, url).await
+    }
+
+    /// Retrieve a threat from its hashkey
+    pub async fn get_threat(&self, hashkey: &str) -> Result<Threat, DatalakeError> {
+        let url = self.settings.routes().threat.replace("{hashkey}", hashkey);
+        let request = self.client.get(&url)
+            .header("Accept", "application/json");
+        self.fetch_threat(request, url).await
+    }
+
+    async fn fetch_threat(&self, request: reqwest::RequestBuilder, url: String) -> Result<Threat, DatalakeError> {
+        let resp = self.run_with_authorization_token(request).await?;
+        let status_code = resp.status();
+        if resp.error_for_status_ref().is_err() {
+            let err = DetailedError {
+                summary: format!("threat API returned error code {status_code}"),
+                api_url: Some(url),
+                api_response: resp.text().await.ok(),
+                api_status_code: Some(status_code),
+            };
+            return Err(ApiError(err));
+        }
+        let json_response = resp.json::<Value>().await?;
+        let api_response = Some(json_response.to_string());
+        match serde_json::from_value::<Threat>(json_response) {
+            Ok(threat) => Ok(threat),
+            Err(_) => {
+                let summary = "threat API response not as expected".to_string();
+                Err(ApiError(DetailedError { summary, api_url: Some(url), api_response, api_status_code: Some(status_code) }))
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use fs4::fs_std::FileExt;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use crate::{DatalakeError, DetailedError};
+use crate::secret::Secret;
+use crate::DatalakeError::ParseError;
+
+/// Session saved in a [TokenStore], tokens keep their "Token " prefix
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StoredTokens {
+    pub access: Secret,
+    pub refresh: Secret,
+}
+
+/// Keeps the session of a user on a Datalake instance, so a new client can reuse it instead of logging in again
+///
+/// Sessions are only stored for username and password authentication, not for long-term tokens.
+pub trait TokenStore: Debug + Send + Sync {
+    fn load(&self, base_url: &str, username: &str) -> Result<Option<StoredTokens>, DatalakeError>;
+
+    fn save(&self, base_url: &str, username: &str, tokens: &StoredTokens) -> Result<(), DatalakeError>;
+}
+
+/// Store shared by the clients of a single process
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    sessions: Mutex<HashMap<(String, String), StoredTokens>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn load(&self, base_url: &str, username: &str) -> Result<Option<StoredTokens>, DatalakeError> {
+        let sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        Ok(sessions.get(&(base_url.to_string(), username.to_string())).cloned())
+    }
+
+    fn save(&self, base_url: &str, username: &str, tokens: &StoredTokens) -> Result<(), DatalakeError> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        sessions.insert((base_url.to_string(), username.to_string()), tokens.clone());
+        Ok(())
+    }
+}
+
+/// Sessions by base_url then by username, as saved in the file
+type StoredSessions = BTreeMap<String, BTreeMap<String, StoredTokens>>;
+
+/// Store in a JSON file readable by its owner only, reused across processes like CLI runs or cron jobs
+#[derive(Debug, Clone)]
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileTokenStore { path: path.into() }
+    }
+
+    fn read_sessions(&self) -> Result<StoredSessions, DatalakeError> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(StoredSessions::new()),
+            Err(err) => return Err(err.into()),
+        };
+        serde_json::from_str(&content).map_err(|err| {
+            ParseError(DetailedError::new(format!("token store {} not as expected: {err}", self.path.display())))
+        })
+    }
+
+    fn directory(&self) -> &Path {
+        self.path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."))
+    }
+
+    /// Advisory lock on a `.lock` file next to the store, released when the returned file is dropped
+    ///
+    /// Held from reading to writing the sessions, so concurrent processes don't drop each other's sessions.
+    fn lock(&self) -> Result<File, DatalakeError> {
+        fs::create_dir_all(self.directory())?;
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_file = OpenOptions::new().write(true).create(true).truncate(false).open(lock_path)?;
+        lock_file.lock_exclusive()?;  // fs4 rather than File::lock, only stable since Rust 1.89
+        Ok(lock_file)
+    }
+
+    /// Replace the file at once through a temporary file unique to this write, so a concurrent process never reads half of it
+    fn write_sessions(&self, sessions: &StoredSessions) -> Result<(), DatalakeError> {
+        let content = serde_json::to_vec_pretty(sessions)
+            .map_err(|err| ParseError(DetailedError::new(format!("could not serialize tokens: {err}"))))?;
+        let mut file = NamedTempFile::new_in(self.directory())?;  // Only readable by its owner on unix
+        file.write_all(&content)?;
+        file.as_file().sync_all()?;
+        file.persist(&self.path).map_err(|err| err.error)?;
+        Ok(())
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self, base_url: &str, username: &str) -> Result<Option<StoredTokens>, DatalakeError> {
+        let sessions = self.read_sessions()?;
+        Ok(sessions.get(base_url).and_then(|users| users.get(username)).cloned())
+    }
+
+    fn save(&self, base_url: &str, username: &str, tokens: &StoredTokens) -> Result<(), DatalakeError> {
+        let _lock = self.lock()?;
+        // An unreadable file would otherwise make every following save fail, the sessions it held are lost anyway
+        let mut sessions = self.read_sessions().unwrap_or_else(|err| {
+            warn!("Overwriting the token store: {err}");
+            StoredSessions::new()
+        });
+        sessions.entry(base_url.to_string()).or_default().insert(username.to_string(), tokens.clone());
+        self.write_sessions(&sessions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::token_store::{FileTokenStore, StoredTokens, TokenStore};
+
+    fn tokens(access: &str) -> StoredTokens {
+        StoredTokens { access: format!("Token {access}").into(), refresh: "Token refresh".into() }
+    }
+
+    #[test]
+    fn test_file_token_store_keeps_one_entry_per_base_url_and_user() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("sessions").join("tokens.json");
+        let store = FileTokenStore::new(&path);
+
+        assert_eq!(store.load("https://prod", "alice").unwrap(), None);
+        store.save("https://prod", "alice", &tokens("alice_prod")).unwrap();
+        store.save("https://preprod", "alice", &tokens("alice_preprod")).unwrap();
+        store.save("https://prod", "bob", &tokens("bob_prod")).unwrap();
+        store.save("https://prod", "alice", &tokens("alice_prod_refreshed")).unwrap();
+
+        let reopened_store = FileTokenStore::new(&path);
+        assert_eq!(reopened_store.load("https://prod", "alice").unwrap(), Some(tokens("alice_prod_refreshed")));
+        assert_eq!(reopened_store.load("https://preprod", "alice").unwrap(), Some(tokens("alice_preprod")));
+        assert_eq!(reopened_store.load("https://prod", "bob").unwrap(), Some(tokens("bob_prod")));
+        assert_eq!(reopened_store.load("https://preprod", "bob").unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_token_store_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("tokens.json");
+        std::fs::write(&path, "{}").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        FileTokenStore::new(&path).save("https://prod", "alice", &tokens("alice")).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_file_token_store_on_invalid_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("tokens.json");
+        std::fs::write(&path, "not json").unwrap();
+
+        let err = FileTokenStore::new(&path).load("https://prod", "alice").unwrap_err();
+
+        assert!(err.to_string().starts_with("Parse Error token store"), "{err}");
+    }
+
+    #[test]
+    fn test_file_token_store_overwrites_invalid_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("tokens.json");
+        std::fs::write(&path, "not json").unwrap();
+        let store = FileTokenStore::new(&path);
+
+        store.save("https://prod", "alice", &tokens("alice")).unwrap();
+
+        assert_eq!(store.load("https://prod", "alice").unwrap(), Some(tokens("alice")));
+    }
+
+    #[test]
+    fn test_file_token_store_concurrent_saves_are_all_kept() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("tokens.json");
+
+        std::thread::scope(|scope| {
+            for user in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        FileTokenStore::new(path).save("https://prod", &format!("user{user}"), &tokens(&format!("user{user}"))).unwrap();
+                    }
+                });
+            }
+        });
+
+        let store = FileTokenStore::new(&path);
+        for user in 0..8 {
+            assert_eq!(store.load("https://prod", &format!("user{user}")).unwrap(), Some(tokens(&format!("user{user}"))));
+        }
+    }
+}
@@ -1,10 +1,11 @@
 /// Contains functions reused across multiple tests
 #[cfg(test)]
-use ocd_datalake_rs::{Datalake, DatalakeSetting};
+use ocd_datalake_rs::{AsyncDatalake, Datalake, DatalakeSetting};
 use std::env;
 
-/// Setup an Datalake reusable across tests
-pub fn create_datalake() -> Datalake {
+/// Setting pointing to the mock server, shared by the blocking and async clients
+#[allow(dead_code)]
+pub fn mock_setting() -> DatalakeSetting {
     let mut setting = DatalakeSetting::prod();
 
     // Speed up tests
@@ -14,10 +15,27 @@ pub fn create_datalake() -> Datalake {
     setting.set_base_url(mockito::server_url());
     env::remove_var("HTTP_PROXY");
     env::remove_var("HTTPS_PROXY");
+    setting
+}
+
+/// Setup an Datalake reusable across tests
+#[allow(dead_code)]  // not every test file uses both clients
+pub fn create_datalake() -> Datalake {
     Datalake::new(
         Some("username".to_string()),
         Some("password".to_string()),
         None,
-        setting,
+        mock_setting(),
+    ).unwrap()
+}
+
+/// Setup an AsyncDatalake reusable across tests
+#[allow(dead_code)]
+pub fn create_async_datalake() -> AsyncDatalake {
+    AsyncDatalake::new(
+        Some("username".to_string()),
+        Some("password".to_string()),
+        None,
+        mock_setting(),
     ).unwrap()
-}
\ No newline at end of file
+}
@@ -0,0 +1,130 @@
+#[path = "common.rs"]
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher::{AllOf, Json, UrlEncoded};
+    use mockito::mock;
+    use serde_json::{json, Value};
+    use ocd_datalake_rs::advanced_search::AdvancedQuery;
+    use crate::common;
+
+    fn query_body() -> Value {
+        json!({
+            "AND": [{
+                "AND": [
+                    {"field": "atom_type", "multi_values": ["ip"], "type": "filter"},
+                    {"field": "tags", "multi_values": ["ransomware"], "type": "filter"}
+                ]
+            }]
+        })
+    }
+
+    fn threats(hashkeys: &[&str]) -> Value {
+        Value::Array(hashkeys.iter().map(|hashkey| json!({"hashkey": hashkey, "atom_type": "ip"})).collect())
+    }
+
+    #[test]
+    fn test_get_query_hash() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let advanced_search_mock = mock("POST", "/mrti/advanced-queries/threats/")
+            .match_header("Authorization", "Token 123")
+            .match_body(Json(json!({"query_body": query_body(), "limit": 1, "offset": 0})))
+            .with_status(200)
+            .with_body(json!({"count": 3, "query_hash": "query_hash123", "results": threats(&["h1"])}).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let query_hash = dtl.get_query_hash(&query_body()).unwrap();
+
+        token_mock.assert();
+        advanced_search_mock.assert();
+        assert_eq!(query_hash, "query_hash123");
+    }
+
+    #[test]
+    fn test_advanced_search_from_hash() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let advanced_search_mock = mock("GET", "/mrti/advanced-queries/threats/query_hash123/")
+            .match_query(AllOf(vec![
+                UrlEncoded("limit".into(), "2".into()),
+                UrlEncoded("offset".into(), "4".into()),
+            ]))
+            .with_status(200)
+            .with_body(json!({"count": 5, "query_hash": "query_hash123", "results": threats(&["h5"])}).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let result = dtl.advanced_search(&AdvancedQuery::Hash("query_hash123".to_string()), 2, 4).unwrap();
+
+        token_mock.assert();
+        advanced_search_mock.assert();
+        assert_eq!(result.count, 5);
+        assert_eq!(result.results.len(), 1);
+        assert_eq!(result.results[0].hashkey, "h5");
+    }
+
+    #[test]
+    fn test_advanced_search_pages() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let first_page_mock = mock("POST", "/mrti/advanced-queries/threats/")
+            .match_body(Json(json!({"query_body": query_body(), "limit": 2, "offset": 0})))
+            .with_status(200)
+            .with_body(json!({"count": 5, "query_hash": "query_hash123", "results": threats(&["h1", "h2"])}).to_string())
+            .create();
+        let second_page_mock = mock("GET", "/mrti/advanced-queries/threats/query_hash123/")
+            .match_query(AllOf(vec![UrlEncoded("limit".into(), "2".into()), UrlEncoded("offset".into(), "2".into())]))
+            .with_status(200)
+            .with_body(json!({"count": 5, "query_hash": "query_hash123", "results": threats(&["h3", "h4"])}).to_string())
+            .create();
+        let last_page_mock = mock("GET", "/mrti/advanced-queries/threats/query_hash123/")
+            .match_query(AllOf(vec![UrlEncoded("limit".into(), "2".into()), UrlEncoded("offset".into(), "4".into())]))
+            .with_status(200)
+            .with_body(json!({"count": 5, "query_hash": "query_hash123", "results": threats(&["h5"])}).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let hashkeys: Vec<String> = dtl.advanced_search_pages(AdvancedQuery::Body(query_body()), 2)
+            .map(|page| page.unwrap())
+            .flat_map(|threats| threats.into_iter().map(|threat| threat.hashkey))
+            .collect();
+
+        for mock in [token_mock, first_page_mock, second_page_mock, last_page_mock] {
+            mock.assert()  // Check url were called 1 times each
+        }
+        assert_eq!(hashkeys, vec!["h1", "h2", "h3", "h4", "h5"]);
+    }
+
+    #[test]
+    fn test_advanced_search_pages_stop_on_error() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let advanced_search_mock = mock("POST", "/mrti/advanced-queries/threats/")
+            .with_status(400)
+            .with_body(r#"{"message":"Invalid query body"}"#)
+            .expect(1)
+            .create();
+        let dtl = common::create_datalake();
+
+        let pages: Vec<_> = dtl.advanced_search_pages(AdvancedQuery::Body(json!({})), 10).collect();
+
+        token_mock.assert();
+        advanced_search_mock.assert();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(
+            pages[0].as_ref().err().unwrap().to_string(),
+            "API Error advanced search returned error code 400 Bad Request"
+        );
+    }
+}
@@ -8,7 +8,9 @@ mod tests {
     use reqwest::StatusCode;
     use serde_json::json;
     use ocd_datalake_rs::{Datalake, DatalakeSetting};
-    use ocd_datalake_rs::error::DatalakeError::ApiError;
+    use ocd_datalake_rs::atom::{AtomType, HashType};
+    use ocd_datalake_rs::error::DatalakeError::{ApiError, AuthenticationError};
+    use ocd_datalake_rs::threat::ThreatType;
     use crate::common;
 
     #[test]
@@ -24,20 +26,20 @@ mod tests {
             .with_status(200)
             .with_body(r#"{"found":2,"not_found":["1.1.1.1"],"results":{"domain":["domain.com"],"ip":["4.4.4.4"]}}"#)
             .create();
-        let mut dtl = common::create_datalake();
-        let atom_values = vec!["domain.com", "4.4.4.4", "1.1.1.1"];
+        let dtl = common::create_datalake();
+        let atom_values = ["domain.com", "4.4.4.4", "1.1.1.1"];
         let atom_values_string: Vec<String> = atom_values.iter().map(|x| x.to_string()).collect();
 
-        let result = dtl.extract_atom_type(&atom_values_string, "file").unwrap();
+        let result = dtl.extract_atom_type(&atom_values_string, HashType::File).unwrap();
 
         // Check mock called happened
         token_mock.assert();
         extract_mock.assert();
 
         let domain = result.get(atom_values[0]).unwrap();
-        assert_eq!(domain, "domain");
+        assert_eq!(domain, &AtomType::Domain);
         let ip1 = result.get(atom_values[1]).unwrap();
-        assert_eq!(ip1, "ip");
+        assert_eq!(ip1, &AtomType::Ip);
         let ip2 = result.get(atom_values[2]);
         assert_eq!(ip2, None);
     }
@@ -54,10 +56,10 @@ mod tests {
             .with_status(200)
             .with_body(r#"{"found":0,"not_found":["123"],"results":{}}"#)
             .create();
-        let mut dtl = common::create_datalake();
-        let atom_values = vec!["123".to_string()];
+        let dtl = common::create_datalake();
+        let atom_values = ["123".to_string()];
 
-        let result = dtl.extract_atom_type(&atom_values, "file").unwrap();
+        let result = dtl.extract_atom_type(&atom_values, HashType::File).unwrap();
 
         // Check mock called happened
         token_mock.assert();
@@ -79,10 +81,10 @@ mod tests {
             .with_status(422)
             .with_body(api_response)
             .create();
-        let mut dtl = common::create_datalake();
-        let atom_values = vec!["123".to_string()];
+        let dtl = common::create_datalake();
+        let atom_values = ["123".to_string()];
 
-        let err = dtl.extract_atom_type(&atom_values, "file").err().unwrap();
+        let err = dtl.extract_atom_type(&atom_values, HashType::File).err().unwrap();
         assert_eq!(
             err.to_string(),
             format!("API Error extracted API response not as expected"),
@@ -111,10 +113,10 @@ mod tests {
             .with_status(200)
             .with_body(api_response)
             .create();
-        let mut dtl = common::create_datalake();
-        let atom_values = vec!["123".to_string()];
+        let dtl = common::create_datalake();
+        let atom_values = ["123".to_string()];
 
-        let err = dtl.extract_atom_type(&atom_values, "file").err().unwrap();
+        let err = dtl.extract_atom_type(&atom_values, HashType::File).err().unwrap();
         assert_eq!(
             err.to_string(),
             format!("API Error extracted API response not as expected"),
@@ -134,7 +136,7 @@ mod tests {
 
     #[test]
     fn test_bulk_lookup_on_few_values() {
-        let atom_values = vec![
+        let atom_values = [
             "620c28ece75af2ea227f195fc45afe109ff9f5c876f2e4da9e0d4f4aad68ee8e",
             "ef3363dfe2515b826584ab53c4bb7812",
             "jeithe7eijeefohch3qu.probes.site",
@@ -185,9 +187,9 @@ mod tests {
             .with_status(200)
             .with_body(csv_body)
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let lookup_result = dtl.bulk_lookup(atom_values_string, "file").unwrap();
+        let lookup_result = dtl.bulk_lookup(atom_values_string, HashType::File).unwrap();
 
         token_mock.assert();
         extract_mock.assert();
@@ -202,14 +204,14 @@ mod tests {
         let mut setting = DatalakeSetting::prod();
         setting.bulk_lookup_chunk_size = 3;
         setting.set_base_url(mockito::server_url());
-        let mut custom_dtl = Datalake::new(
+        let custom_dtl = Datalake::new(
             Some("username".to_string()),
             Some("password".to_string()),
             None,
             setting,
         ).unwrap();
 
-        let atom_values = vec![
+        let atom_values = [
             "620c28ece75af2ea227f195fc45afe109ff9f5c876f2e4da9e0d4f4aad68ee8e",
             "jeithe7eijeefohch3qu.probes.site",
             "ef3363dfe2515b826584ab53c4bb7812",  // <- end of first chunk
@@ -309,7 +311,7 @@ mod tests {
                 }))
             )
             .with_status(200)
-            .with_body(&[csv_header, csv_content_2].join("\n"))
+            .with_body([csv_header, csv_content_2].join("\n"))
             .create();
         let csv_content_3 = "csv_content_3";
         let lookup_mock_3 = mock("POST", "/mrti/threats/bulk-lookup/")
@@ -321,10 +323,10 @@ mod tests {
                 }))
             )
             .with_status(200)
-            .with_body(&[csv_header, csv_content_3].join("\n"))
+            .with_body([csv_header, csv_content_3].join("\n"))
             .create();
 
-        let lookup_result = custom_dtl.bulk_lookup(atom_values_string, "file").unwrap();
+        let lookup_result = custom_dtl.bulk_lookup(atom_values_string, HashType::File).unwrap();
 
         token_mock.assert();
         extract_mock_1.assert();
@@ -354,14 +356,14 @@ mod tests {
         setting.set_base_url(mockito::server_url());
         std::env::remove_var("HTTP_PROXY");
         std::env::remove_var("HTTPS_PROXY");
-        let mut custom_dtl = Datalake::new(
+        let custom_dtl = Datalake::new(
             None,
             None,
             Some("longterm_token".to_string()),
             setting,
         ).unwrap();
 
-        let atom_values = vec![
+        let atom_values = [
             "620c28ece75af2ea227f195fc45afe109ff9f5c876f2e4da9e0d4f4aad68ee8e",
             "jeithe7eijeefohch3qu.probes.site",
             "ef3363dfe2515b826584ab53c4bb7812",
@@ -439,7 +441,7 @@ mod tests {
             .with_body(incorrect_csv_returned)
             .create();
 
-        let error = custom_dtl.bulk_lookup(atom_values_string, "file").err().unwrap();
+        let error = custom_dtl.bulk_lookup(atom_values_string, HashType::File).err().unwrap();
         assert_eq!(error.to_string(), "API Error unexpected csv result, missing body".to_string());
         match error {
             ApiError(details) => {
@@ -456,7 +458,7 @@ mod tests {
 
     #[test]
     fn test_bulk_lookup_error() {
-        let atom_values = vec![
+        let atom_values = [
             "620c28ece75af2ea227f195fc45afe109ff9f5c876f2e4da9e0d4f4aad68ee8e",
             "ef3363dfe2515b826584ab53c4bb7812",
             "jeithe7eijeefohch3qu.probes.site",
@@ -474,9 +476,9 @@ mod tests {
             .with_body(api_response)
             .create();
         let lookup_mock = mock("POST", "/mrti/threats/bulk-lookup/").create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let err = dtl.bulk_lookup(atom_values_string, "file").err().unwrap();
+        let err = dtl.bulk_lookup(atom_values_string, HashType::File).err().unwrap();
         assert_eq!(err.to_string(), format!("API Error extracted API response not as expected"));
         if let ApiError(detailed_err) = err {
             assert_eq!(detailed_err.api_response.unwrap(), api_response);
@@ -489,4 +491,205 @@ mod tests {
         extract_mock.assert();
         lookup_mock.expect_at_most(0).assert();  // Lookup is not called if extract failed
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bulk_lookup_typed() {
+        // Use chunks of 1 value to check results of all chunks are returned in order
+        let mut setting = DatalakeSetting::prod();
+        setting.bulk_lookup_chunk_size = 1;
+        setting.set_base_url(mockito::server_url());
+        let custom_dtl = Datalake::new(
+            None,
+            None,
+            Some("longterm_token".to_string()),
+            setting,
+        ).unwrap();
+        let atom_values_string = vec!["jeithe7eijeefohch3qu.probes.site".to_string(), "ef3363dfe2515b826584ab53c4bb7812".to_string()];
+
+        let extract_mock_1 = mock("POST", "/mrti/threats/atom-values-extract/")
+            .match_body(Json(json!({"content": "jeithe7eijeefohch3qu.probes.site", "treat_hashes_like": "file"})))
+            .with_status(200)
+            .with_body(json!({"found": 1, "not_found": [], "results": {"fqdn": ["jeithe7eijeefohch3qu.probes.site"]}}).to_string())
+            .create();
+        let extract_mock_2 = mock("POST", "/mrti/threats/atom-values-extract/")
+            .match_body(Json(json!({"content": "ef3363dfe2515b826584ab53c4bb7812", "treat_hashes_like": "file"})))
+            .with_status(200)
+            .with_body(json!({"found": 1, "not_found": [], "results": {"file": ["ef3363dfe2515b826584ab53c4bb7812"]}}).to_string())
+            .create();
+        let csv_header = "hashkey,atom_type,search_phrase,atom_value,threat_found,malware.score.risk,phishing.score.risk,sources,tags";
+        let lookup_mock_1 = mock("POST", "/mrti/threats/bulk-lookup/")
+            .match_body(Json(json!({"hashkey_only": false, "fqdn": ["jeithe7eijeefohch3qu.probes.site"]})))
+            .match_header("Accept", "text/csv")
+            .with_status(200)
+            .with_body(format!("{csv_header}\n570c18ccf35a7003789f4332cb63bfce,fqdn,jeithe7eijeefohch3qu.probes.site,jeithe7eijeefohch3qu.probes.site,True,,12,src_1,phishing\n"))
+            .create();
+        let lookup_mock_2 = mock("POST", "/mrti/threats/bulk-lookup/")
+            .match_body(Json(json!({"hashkey_only": false, "file": ["ef3363dfe2515b826584ab53c4bb7812"]})))
+            .with_status(200)
+            .with_body(format!("{csv_header}\n736e1acf892a27598d65a52136122699,,ef3363dfe2515b826584ab53c4bb7812,,False,,,,"))
+            .create();
+
+        let results = custom_dtl.bulk_lookup_typed(atom_values_string, HashType::File).unwrap();
+
+        extract_mock_1.assert();
+        extract_mock_2.assert();
+        lookup_mock_1.assert();
+        lookup_mock_2.assert();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].atom_value, "jeithe7eijeefohch3qu.probes.site");
+        assert!(results[0].found);
+        assert_eq!(results[0].scores.get(&ThreatType::Phishing), Some(&12));
+        assert_eq!(results[0].sources, ["src_1"]);
+        assert_eq!(results[0].tags, ["phishing"]);
+        assert_eq!(results[1].atom_value, "ef3363dfe2515b826584ab53c4bb7812");
+        assert!(!results[1].found);
+        assert_eq!(results[1].hashkey, "736e1acf892a27598d65a52136122699");
+    }
+
+    fn parallel_datalake(bulk_lookup_parallelism: usize) -> Datalake {
+        let mut setting = DatalakeSetting::prod();
+        setting.bulk_lookup_chunk_size = 1;
+        setting.bulk_lookup_parallelism = bulk_lookup_parallelism;
+        setting.set_base_url(mockito::server_url());
+        Datalake::new(None, None, Some("longterm_token".to_string()), setting).unwrap()
+    }
+
+    #[test]
+    fn test_bulk_lookup_in_parallel_keeps_order() {
+        let dtl = parallel_datalake(4);
+        let atom_values: Vec<String> = (1..=4).map(|i| format!("probes{i}.site")).collect();
+        let csv_header = "hashkey,atom_type,search_phrase,atom_value,threat_found";
+        let mut mocks = Vec::new();
+        for (index, atom_value) in atom_values.iter().enumerate() {
+            mocks.push(mock("POST", "/mrti/threats/atom-values-extract/")
+                .match_body(Json(json!({"content": atom_value, "treat_hashes_like": "file"})))
+                .with_status(200)
+                .with_body(json!({"found": 1, "not_found": [], "results": {"domain": [atom_value]}}).to_string())
+                .create());
+            let csv = format!("{csv_header}\nhashkey{index},domain,{atom_value},{atom_value},True\n");
+            mocks.push(mock("POST", "/mrti/threats/bulk-lookup/")
+                .match_body(Json(json!({"hashkey_only": false, "domain": [atom_value]})))
+                .with_status(200)
+                .with_body_from_fn(move |writer| {
+                    if index == 0 {
+                        std::thread::sleep(std::time::Duration::from_millis(200));  // First chunk answers last
+                    }
+                    writer.write_all(csv.as_bytes())
+                })
+                .create());
+        }
+
+        let csv_result = dtl.bulk_lookup(atom_values.clone(), HashType::File).unwrap();
+
+        for mock in &mocks {
+            mock.assert();
+        }
+        let rows: Vec<&str> = csv_result.lines().collect();
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0], csv_header);
+        for (index, atom_value) in atom_values.iter().enumerate() {
+            assert_eq!(rows[index + 1], format!("hashkey{index},domain,{atom_value},{atom_value},True"));
+        }
+    }
+
+    #[test]
+    fn test_bulk_lookup_with_zero_parallelism_looks_up_one_chunk_at_a_time() {
+        let dtl = parallel_datalake(0);  // Set on the public field, so not validated
+        let extract_mock = mock("POST", "/mrti/threats/atom-values-extract/")
+            .with_status(200)
+            .with_body(json!({"found": 1, "not_found": [], "results": {"domain": ["probes.site"]}}).to_string())
+            .expect(2)
+            .create();
+        let bulk_lookup_mock = mock("POST", "/mrti/threats/bulk-lookup/")
+            .with_status(200)
+            .with_body("hashkey,atom_value\nhashkey,probes.site\n")
+            .expect(2)
+            .create();
+
+        let csv_result = dtl.bulk_lookup(vec!["probes.site".to_string(), "probes.site".to_string()], HashType::File).unwrap();
+
+        assert_eq!(csv_result.lines().count(), 3);
+        extract_mock.assert();
+        bulk_lookup_mock.assert();
+    }
+
+    #[test]
+    fn test_bulk_lookup_in_parallel_stops_on_auth_failure() {
+        let dtl = parallel_datalake(2);
+        let atom_values: Vec<String> = (1..=6).map(|i| format!("probes{i}.site")).collect();
+        let extract_mock = mock("POST", "/mrti/threats/atom-values-extract/")
+            .with_status(401)
+            .with_body(r#"{"message":"Invalid token"}"#)
+            .create();
+        let lookup_mock = mock("POST", "/mrti/threats/bulk-lookup/").create();
+
+        let err = dtl.bulk_lookup(atom_values, HashType::File).unwrap_err();
+
+        let AuthenticationError(detailed_err) = err else { panic!("Unexpected error {err:?}") };
+        assert_eq!(detailed_err.summary, "401 response : invalid long-term token");
+        extract_mock.expect_at_least(1).expect_at_most(2).assert();  // Only the chunks already started are sent
+        lookup_mock.expect_at_most(0).assert();
+    }
+
+    #[test]
+    fn test_bulk_lookup_partial_goes_on_after_failed_chunk() {
+        let dtl = parallel_datalake(1);
+        let atom_values: Vec<String> = (1..=3).map(|i| format!("probes{i}.site")).collect();
+        let csv_header = "hashkey,atom_type,search_phrase,atom_value,threat_found";
+        let mut mocks = Vec::new();
+        for (index, atom_value) in atom_values.iter().enumerate() {
+            if index == 1 {
+                mocks.push(mock("POST", "/mrti/threats/atom-values-extract/")
+                    .match_body(Json(json!({"content": atom_value, "treat_hashes_like": "file"})))
+                    .with_status(429)
+                    .with_body(r#"{"message":"Too many requests"}"#)
+                    .create());
+                continue;
+            }
+            mocks.push(mock("POST", "/mrti/threats/atom-values-extract/")
+                .match_body(Json(json!({"content": atom_value, "treat_hashes_like": "file"})))
+                .with_status(200)
+                .with_body(json!({"found": 1, "not_found": [], "results": {"domain": [atom_value]}}).to_string())
+                .create());
+            mocks.push(mock("POST", "/mrti/threats/bulk-lookup/")
+                .match_body(Json(json!({"hashkey_only": false, "domain": [atom_value]})))
+                .with_status(200)
+                .with_body(format!("{csv_header}\nhashkey{index},domain,{atom_value},{atom_value},True\n"))
+                .create());
+        }
+
+        let report = dtl.bulk_lookup_partial(atom_values, HashType::File);
+
+        for mock in &mocks {
+            mock.assert();
+        }
+        assert!(!report.is_complete());
+        assert_eq!(report.csv, format!(
+            "{csv_header}\nhashkey0,domain,probes1.site,probes1.site,True\nhashkey2,domain,probes3.site,probes3.site,True\n"
+        ));
+        assert_eq!(report.unprocessed_atom_values, ["probes2.site"]);
+        assert_eq!(report.chunk_errors.len(), 1);
+        assert_eq!(report.chunk_errors[0].chunk_index, 1);
+        assert_eq!(report.chunk_errors[0].atom_values, ["probes2.site"]);
+        assert_eq!(report.chunk_errors[0].error.to_string(), "API Error extracted API response not as expected");
+    }
+
+    #[test]
+    fn test_bulk_lookup_partial_stops_on_auth_failure() {
+        let dtl = parallel_datalake(1);
+        let atom_values: Vec<String> = (1..=3).map(|i| format!("probes{i}.site")).collect();
+        let extract_mock = mock("POST", "/mrti/threats/atom-values-extract/")
+            .with_status(401)
+            .with_body(r#"{"message":"Invalid token"}"#)
+            .expect(1)
+            .create();
+
+        let report = dtl.bulk_lookup_partial(atom_values.clone(), HashType::File);
+
+        extract_mock.assert();
+        assert!(report.csv.is_empty());
+        assert_eq!(report.chunk_errors.len(), 1);
+        assert!(matches!(report.chunk_errors[0].error, AuthenticationError(_)));
+        assert_eq!(report.unprocessed_atom_values, atom_values);  // Skipped chunks are to be retried too
+    }
+}
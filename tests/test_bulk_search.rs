@@ -3,16 +3,28 @@ mod common;
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::future::{self, Future};
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration as StdDuration;
+    use chrono::{DateTime, Duration, TimeZone, Utc};
     use mockito::mock;
-    use mockito::Matcher::Json;
+    use mockito::Matcher::{Json, Missing};
     use reqwest::StatusCode;
     use rstest::rstest;
     use serde_json::json;
 
     use ocd_datalake_rs::bulk_search::{
-        create_bulk_search_task, download_bulk_search, get_bulk_search_task, BulkSearchTask, State,
+        cancel_bulk_search_task, create_bulk_search_task, create_bulk_search_task_as, download_bulk_search,
+        download_bulk_search_as, download_bulk_search_json, download_bulk_search_to, get_bulk_search_task,
+        list_bulk_search_tasks, BulkSearchHandle, BulkSearchTask, BulkSearchTaskFilter, ExportFormat, QueryField, State,
     };
-    use ocd_datalake_rs::error::DatalakeError::ApiError;
+    use ocd_datalake_rs::atom::AtomType;
+    use ocd_datalake_rs::polling::Clock;
+    use ocd_datalake_rs::threat::ThreatType;
+    use ocd_datalake_rs::{Datalake, DatalakeSetting, PollingStrategy};
+    use ocd_datalake_rs::error::DatalakeError::{ApiError, IoError, ParseError};
     use ocd_datalake_rs::error::DetailedError;
 
     use crate::common;
@@ -83,7 +95,7 @@ mod tests {
                 .with_status(200)
                 .with_body(bulk_search_response_expected.clone())
                 .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let task_created = dtl.bulk_search(query_hash, query_fields).unwrap();
 
@@ -122,10 +134,10 @@ mod tests {
                 .to_string(),
             )
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let task_uuid =
-            create_bulk_search_task(&mut dtl, query_hash.clone(), query_fields.clone()).unwrap();
+            create_bulk_search_task(&dtl, query_hash.clone(), query_fields.clone()).unwrap();
 
         token_mock.assert();
         bulk_search_mock.assert();
@@ -156,9 +168,9 @@ mod tests {
                 .to_string(),
             )
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let error = create_bulk_search_task(&mut dtl, query_hash.clone(), query_fields.clone())
+        let error = create_bulk_search_task(&dtl, query_hash.clone(), query_fields.clone())
             .err()
             .unwrap();
 
@@ -206,7 +218,7 @@ mod tests {
                         "created_at": created_at,
                         "eta": null,
                         "file_delete_after": "2022-08-25T07:11:57.797385+00:00",
-                        "file_deleted": false,  // Some extra fields are present but not yet saved
+                        "file_deleted": false,
                         "file_size": 252,
                         "finished_at": finished_at,
                         "progress": null,
@@ -220,21 +232,29 @@ mod tests {
                 .to_string(),
             )
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let task_created = get_bulk_search_task(&mut dtl, task_uid.to_string()).unwrap();
+        let task_created = get_bulk_search_task(&dtl, task_uid.to_string()).unwrap();
 
         token_mock.assert();
         bulk_search_task_mock.assert();
 
         let expected_task = BulkSearchTask {
-            created_at: created_at.to_string(),
-            started_at: Some(started_at.to_string()),
-            finished_at: Some(finished_at.to_string()),
+            uuid: task_uid.to_string(),
+            state: State::DONE,
+            bulk_search_hash: Some("0ff239b3dd01cec5cd8343a7e9f1ae84".to_string()),
+            bulk_search: None,
+            created_at: created_at.parse().unwrap(),
+            started_at: Some(started_at.parse().unwrap()),
+            finished_at: Some(finished_at.parse().unwrap()),
+            eta: None,
+            progress: None,
             queue_position: None,
             results: Some(results_number),
-            state: state.to_string(),
-            uuid: task_uid.to_string(),
+            file_size: Some(252),
+            file_deleted: false,
+            file_delete_after: Some(Utc.with_ymd_and_hms(2022, 8, 25, 7, 11, 57).unwrap() + Duration::microseconds(797385)),
+            user: None,
         };
         assert_eq!(task_created, expected_task)
     }
@@ -291,13 +311,27 @@ mod tests {
                 .to_string(),
             )
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let task_created = get_bulk_search_task(&mut dtl, "task_uid123".to_string()).unwrap();
+        let task_created = get_bulk_search_task(&dtl, "task_uid123".to_string()).unwrap();
 
         token_mock.assert();
         bulk_search_task_mock.assert();
-        assert_eq!(task_created.state, "NEW")
+        assert_eq!(task_created.state, State::NEW);
+        assert_eq!(task_created.progress, Some(0.0));
+        assert_eq!(task_created.eta, Some("2022-08-24T06:54:40.760737+00:00".parse().unwrap()));
+        assert_eq!(task_created.file_size, None);
+        let bulk_search = task_created.bulk_search.as_ref().unwrap();
+        assert_eq!(bulk_search.query_hash, "fbecd3d440a7d439a2a1fd996c703a8d");
+        assert_eq!(bulk_search.query_fields, ["atom_value"]);
+        assert!(!bulk_search.for_stix_export);
+        let user = task_created.user.as_ref().unwrap();
+        assert_eq!(user.id, 287);
+        assert_eq!(user.email, "hugo.chastel@orange.com");
+        assert_eq!(user.organization.as_ref().unwrap().path_names, ["OCD"]);
+        let round_trip = serde_json::to_value(&task_created).unwrap();
+        assert_eq!(round_trip["bulk_search"]["advanced_query_hash"], "fbecd3d440a7d439a2a1fd996c703a8d");
+        assert_eq!(serde_json::from_value::<BulkSearchTask>(round_trip).unwrap(), task_created);
     }
 
     #[test]
@@ -314,9 +348,9 @@ mod tests {
             .with_status(200)
             .with_body(json!({ "unexpected json": true }).to_string())
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let error = get_bulk_search_task(&mut dtl, task_uid.to_string())
+        let error = get_bulk_search_task(&dtl, task_uid.to_string())
             .err()
             .unwrap();
 
@@ -344,9 +378,9 @@ mod tests {
                 .with_status(200)
                 .with_body(bulk_search_response_expected.clone())
                 .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let bulk_search_response = download_bulk_search(&mut dtl, task_uid.to_string()).unwrap();
+        let bulk_search_response = download_bulk_search(&dtl, task_uid.to_string()).unwrap();
 
         token_mock.assert();
         bulk_search_task_mock.assert();
@@ -365,9 +399,9 @@ mod tests {
                 .with_status(202) // 202 means not ready
                 .with_body("bulk search is not ready")
                 .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let error = download_bulk_search(&mut dtl, task_uid.to_string())
+        let error = download_bulk_search(&dtl, task_uid.to_string())
             .err()
             .unwrap();
 
@@ -394,9 +428,9 @@ mod tests {
                 .with_status(404)
                 .with_body("url not found")
                 .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
-        let error = download_bulk_search(&mut dtl, task_uid.to_string())
+        let error = download_bulk_search(&dtl, task_uid.to_string())
             .err()
             .unwrap();
 
@@ -499,7 +533,7 @@ mod tests {
                 .with_status(200)
                 .with_body(bulk_search_response_expected.clone())
                 .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let task_created = dtl.bulk_search(query_hash, query_fields).unwrap();
 
@@ -575,7 +609,7 @@ mod tests {
                 .with_body("some body")
                 .expect(0) // Download should not be called
                 .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let error = dtl.bulk_search(query_hash, query_fields).err().unwrap();
 
@@ -651,7 +685,7 @@ mod tests {
                 .with_body("some body")
                 .expect(0) // Download should not be called
                 .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let error = dtl.bulk_search(query_hash, query_fields).err().unwrap();
 
@@ -728,7 +762,7 @@ mod tests {
                 .with_body("some body")
                 .expect(0) // Download should not be called
                 .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let error = dtl.bulk_search(query_hash, query_fields).err().unwrap();
 
@@ -742,4 +776,853 @@ mod tests {
             format!("Timeout Error Bulk search is not finished after 1 seconds")
         );
     }
+
+    fn task_json(task_uid: &str, state: &str) -> String {
+        task_json_with_file_size(task_uid, state, None)
+    }
+
+    fn task_json_with_file_size(task_uid: &str, state: &str, file_size: Option<usize>) -> String {
+        json!({
+            "count": 1,
+            "results": [{
+                "created_at": "2022-08-22T07:11:32.011836+00:00",
+                "file_size": file_size,
+                "finished_at": null,
+                "queue_position": null,
+                "results": null,
+                "started_at": null,
+                "state": state,
+                "uuid": task_uid,
+            }]
+        }).to_string()
+    }
+
+    #[test]
+    fn test_start_bulk_search_poll_and_cancel() {
+        let task_uid = "task_uuid123";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_mock = mock("POST", "/mrti/bulk-search/")
+            .with_status(200)
+            .with_body(json!({"task_uuid": task_uid}).to_string())
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(task_json(task_uid, "QUEUED"))
+            .create();
+        let cancel_mock = mock("POST", format!("/mrti/bulk-search/task/{task_uid}/cancel/").as_str())
+            .match_header("Authorization", "Token 123")
+            .with_status(200)
+            .with_body("{}")
+            .create();
+        let dtl = common::create_datalake();
+
+        let handle = dtl.start_bulk_search("query_hash123".to_string(), vec!["atom_value".to_string()]).unwrap();
+        assert_eq!(handle.task_uuid(), task_uid);
+        let task = handle.poll().unwrap();
+        assert_eq!(task.state, State::QUEUED);
+        handle.cancel().unwrap();
+
+        token_mock.assert();
+        bulk_search_mock.assert();
+        bulk_search_task_mock.assert();
+        cancel_mock.assert();
+    }
+
+    #[test]
+    fn test_bulk_search_handle_from_uuid_wait_with() {
+        let task_uid = "task_uuid456";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_mock = mock("POST", "/mrti/bulk-search/").create();
+        let in_progress_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(task_json(task_uid, "IN_PROGRESS"))
+            .expect(1)
+            .create();
+        let done_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(task_json(task_uid, "DONE"))
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(200)
+            .with_body("some bulk search csv result")
+            .create();
+        let dtl = common::create_datalake();
+
+        let handle = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string());
+        let mut polled_states = Vec::new();
+        let result = handle.wait_with(|task| polled_states.push(task.state.clone())).unwrap();
+
+        assert_eq!(result, "some bulk search csv result");
+        assert_eq!(polled_states, [State::IN_PROGRESS, State::DONE]);
+        token_mock.assert();
+        bulk_search_mock.expect_at_most(0).assert();  // Resuming a task does not submit it again
+        in_progress_mock.assert();
+        done_mock.assert();
+        download_mock.assert();
+    }
+
+    #[test]
+    fn test_bulk_search_handle_cancel_on_error() {
+        let task_uid = "task_uuid789";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let cancel_mock = mock("POST", format!("/mrti/bulk-search/task/{task_uid}/cancel/").as_str())
+            .with_status(404)
+            .with_body(r#"{"message":"Not found"}"#)
+            .create();
+        let dtl = common::create_datalake();
+
+        let err = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string()).cancel().unwrap_err();
+
+        token_mock.assert();
+        cancel_mock.assert();
+        assert_eq!(err.to_string(), format!("API Error bulk search with task uuid: {task_uid} could not be cancelled, error code 404 Not Found"));
+    }
+
+    #[test]
+    fn test_bulk_search_download_to_writer() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let task_uid = "task_uuid123";
+        let csv_result = "atom_value\n".to_string() + &"8.8.8.8\n".repeat(10_000);
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .match_header("Accept", "text/csv")
+            .with_status(200)
+            .with_body(&csv_result)
+            .create();
+        let dtl = common::create_datalake();
+        let mut output = Vec::new();
+
+        let bytes_written = download_bulk_search_to(&dtl, task_uid.to_string(), ExportFormat::Csv, &mut output).unwrap();
+
+        token_mock.assert();
+        download_mock.assert();
+        assert_eq!(bytes_written, csv_result.len() as u64);
+        assert_eq!(String::from_utf8(output).unwrap(), csv_result);
+    }
+
+    #[tokio::test]
+    async fn test_async_bulk_search_download_json_to_writer() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let task_uid = "task_uuid123";
+        let json_result = r#"[{"atom_value": "8.8.8.8"}]"#;
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .match_header("Accept", "application/json")
+            .with_status(200)
+            .with_body(json_result)
+            .create();
+        let dtl = common::create_async_datalake();
+        let mut output = Vec::new();
+
+        let bytes_written = dtl.download_bulk_search_to(task_uid.to_string(), ExportFormat::Json, &mut output).await.unwrap();
+
+        token_mock.assert();
+        download_mock.assert();
+        assert_eq!(bytes_written, json_result.len() as u64);
+        assert_eq!(String::from_utf8(output).unwrap(), json_result);
+    }
+
+    #[test]
+    fn test_bulk_search_to_file() {
+        let task_uid = "task_uuid123";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_mock = mock("POST", "/mrti/bulk-search/")
+            .with_status(200)
+            .with_body(json!({"task_uuid": task_uid}).to_string())
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .with_status(200)
+            .with_body(task_json(task_uid, "DONE"))
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(200)
+            .with_body("some bulk search csv result")
+            .create();
+        let dtl = common::create_datalake();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("result.csv");
+
+        let bytes_written = dtl.bulk_search_to_file("query_hash123".to_string(), vec!["atom_value".to_string()], &output_path).unwrap();
+
+        token_mock.assert();
+        bulk_search_mock.assert();
+        bulk_search_task_mock.assert();
+        download_mock.assert();
+        assert_eq!(bytes_written, 27);
+        assert_eq!(fs::read_to_string(&output_path).unwrap(), "some bulk search csv result");
+        assert_eq!(fs::read_dir(output_dir.path()).unwrap().count(), 1);  // Temporary file was renamed
+    }
+
+    #[test]
+    fn test_bulk_search_to_file_leaves_no_file_on_error() {
+        let task_uid = "task_uuid123";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(404)
+            .with_body("Not Found")
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .with_status(200)
+            .with_body(task_json(task_uid, "DONE"))
+            .create();
+        let dtl = common::create_datalake();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("result.csv");
+
+        let handle = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string());
+        let err = handle.wait_to_file(&output_path, |_| {}).unwrap_err();
+
+        token_mock.assert();
+        bulk_search_task_mock.assert();
+        download_mock.assert();
+        assert_eq!(err.to_string(), format!("API Error bulk search with task uuid: {task_uid} returned error code 404 Not Found"));
+        assert_eq!(fs::read_dir(output_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_bulk_search_to_file_on_unwritable_path() {
+        let task_uid = "task_uuid123";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .with_status(200)
+            .with_body(task_json(task_uid, "DONE"))
+            .create();
+        let dtl = common::create_datalake();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("missing_dir").join("result.csv");
+
+        let err = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string()).wait_to_file(&output_path, |_| {}).unwrap_err();
+
+        token_mock.assert();
+        bulk_search_task_mock.assert();
+        assert!(matches!(err, IoError(_)), "Unexpected error {err:?}");
+    }
+
+    #[test]
+    fn test_bulk_search_to_file_resumes_interrupted_download() {
+        let task_uid = "task_uuid123";
+        let (first_part, second_part) = ("atom_value\n8.8.8.8\n", "1.1.1.1\n");
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .with_status(200)
+            .with_body(task_json_with_file_size(task_uid, "DONE", Some(first_part.len() + second_part.len())))
+            .create();
+        let interrupted_download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .match_header("Range", Missing)
+            .with_status(200)
+            .with_header("content-length", "1000")  // The connection is closed before the announced length is sent
+            .with_body(first_part)
+            .create();
+        let resumed_download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .match_header("Range", format!("bytes={}-", first_part.len()).as_str())
+            .with_status(206)
+            .with_body(second_part)
+            .create();
+        let dtl = common::create_datalake();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("result.csv");
+
+        let bytes_written = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string()).wait_to_file(&output_path, |_| {}).unwrap();
+
+        token_mock.assert();
+        bulk_search_task_mock.assert();
+        interrupted_download_mock.assert();
+        resumed_download_mock.assert();
+        assert_eq!(bytes_written, (first_part.len() + second_part.len()) as u64);
+        assert_eq!(fs::read_to_string(&output_path).unwrap(), format!("{first_part}{second_part}"));
+    }
+
+    #[test]
+    fn test_bulk_search_to_file_retries_on_server_error() {
+        let task_uid = "task_uuid123";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .with_status(200)
+            .with_body(task_json_with_file_size(task_uid, "DONE", Some(27)))
+            .create();
+        let server_error_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(503)
+            .expect(2)
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(200)
+            .with_body("some bulk search csv result")
+            .create();
+        let dtl = common::create_datalake();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("result.csv");
+
+        let bytes_written = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string()).wait_to_file(&output_path, |_| {}).unwrap();
+
+        token_mock.assert();
+        bulk_search_task_mock.assert();
+        server_error_mock.assert();
+        download_mock.assert();
+        assert_eq!(bytes_written, 27);
+    }
+
+    #[test]
+    fn test_bulk_search_to_file_on_size_mismatch() {
+        let task_uid = "task_uuid123";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .with_status(200)
+            .with_body(task_json_with_file_size(task_uid, "DONE", Some(252)))
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(200)
+            .with_body("some bulk search csv result")
+            .create();
+        let dtl = common::create_datalake();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("result.csv");
+
+        let err = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string()).wait_to_file(&output_path, |_| {}).unwrap_err();
+
+        token_mock.assert();
+        bulk_search_task_mock.assert();
+        download_mock.assert();
+        assert_eq!(err.to_string(), format!("API Error bulk search with task uuid: {task_uid} downloaded 27 bytes instead of 252"));
+        assert_eq!(fs::read_dir(output_dir.path()).unwrap().count(), 0);
+    }
+
+    fn listed_task(task_uid: &str, state: &str, created_at: &str, query_hash: &str) -> serde_json::Value {
+        json!({
+            "bulk_search": {"advanced_query_hash": query_hash, "for_stix_export": false, "query_fields": ["atom_value"]},
+            "created_at": created_at,
+            "state": state,
+            "uuid": task_uid,
+        })
+    }
+
+    #[test]
+    fn test_list_bulk_search_tasks() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let first_page_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"limit": 100, "offset": 0})))
+            .with_status(200)
+            .with_body(json!({"count": 3, "results": [
+                listed_task("task_1", "DONE", "2022-08-24T06:54:39+00:00", "query_hash123"),
+                listed_task("task_2", "IN_PROGRESS", "2022-08-24T06:54:39+00:00", "query_hash123"),
+            ]}).to_string())
+            .create();
+        let second_page_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"limit": 100, "offset": 2})))
+            .with_status(200)
+            .with_body(json!({"count": 3, "results": [
+                listed_task("task_3", "DONE", "2022-08-20T06:54:39+00:00", "query_hash123"),
+            ]}).to_string())
+            .create();
+        let dtl = common::create_datalake();
+        let filter = BulkSearchTaskFilter {
+            states: vec![State::DONE],
+            created_after: Some(Utc.with_ymd_and_hms(2022, 8, 22, 0, 0, 0).unwrap()),
+            query_hash: Some("query_hash123".to_string()),
+            ..Default::default()
+        };
+
+        let tasks = list_bulk_search_tasks(&dtl, &filter).unwrap();
+
+        token_mock.assert();
+        first_page_mock.assert();
+        second_page_mock.assert();
+        let task_uuids: Vec<&str> = tasks.iter().map(|task| task.uuid.as_str()).collect();
+        assert_eq!(task_uuids, ["task_1"]);
+    }
+
+    #[test]
+    fn test_list_bulk_search_tasks_with_unknown_state() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let list_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"limit": 100, "offset": 0})))
+            .with_status(200)
+            .with_body(json!({"count": 2, "results": [
+                listed_task("task_1", "DONE", "2022-08-24T06:54:39+00:00", "query_hash123"),
+                listed_task("task_2", "ARCHIVED", "2022-08-24T06:54:39+00:00", "query_hash123"),  // Added to the API later
+            ]}).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let tasks = list_bulk_search_tasks(&dtl, &BulkSearchTaskFilter::default()).unwrap();
+
+        token_mock.assert();
+        list_mock.assert();
+        let states: Vec<&State> = tasks.iter().map(|task| &task.state).collect();
+        assert_eq!(states, [&State::DONE, &State::Unknown("ARCHIVED".to_string())]);
+        assert_eq!(serde_json::to_value(&tasks[1].state).unwrap(), json!("ARCHIVED"));
+    }
+
+    #[test]
+    fn test_list_bulk_search_tasks_by_query_hash() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let tasks_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .with_status(200)
+            .with_body(json!({"count": 2, "results": [
+                listed_task("task_1", "DONE", "2022-08-24T06:54:39+00:00", "query_hash123"),
+                listed_task("task_2", "DONE", "2022-08-24T06:54:39+00:00", "other_query_hash"),
+            ]}).to_string())
+            .create();
+        let dtl = common::create_datalake();
+        let filter = BulkSearchTaskFilter { query_hash: Some("other_query_hash".to_string()), ..Default::default() };
+
+        let tasks = list_bulk_search_tasks(&dtl, &filter).unwrap();
+
+        token_mock.assert();
+        tasks_mock.assert();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].uuid, "task_2");
+    }
+
+    #[test]
+    fn test_cancel_bulk_search_task() {
+        let task_uid = "task_uuid123";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let cancel_mock = mock("POST", format!("/mrti/bulk-search/task/{task_uid}/cancel/").as_str())
+            .match_header("Authorization", "Token 123")
+            .with_status(200)
+            .with_body("{}")
+            .create();
+        let dtl = common::create_datalake();
+
+        cancel_bulk_search_task(&dtl, task_uid.to_string()).unwrap();
+
+        token_mock.assert();
+        cancel_mock.assert();
+    }
+
+    #[test]
+    fn test_bulk_search_create_task_for_stix_export() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_mock = mock("POST", "/mrti/bulk-search/")
+            .match_header("Accept", "application/stix+json")
+            .match_body(Json(json!({
+                "query_hash": "query_hash123",
+                "query_fields": ["atom_value"],
+                "for_stix_export": true,
+            })))
+            .with_status(200)
+            .with_body(json!({"task_uuid": "task_uuid_stix", "for_stix_export": true}).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let task_uuid = create_bulk_search_task_as(
+            &dtl, "query_hash123".to_string(), vec!["atom_value".to_string()], ExportFormat::Stix,
+        ).unwrap();
+
+        token_mock.assert();
+        bulk_search_mock.assert();
+        assert_eq!(task_uuid, "task_uuid_stix");
+    }
+
+    #[test]
+    fn test_bulk_search_download_as_stix() {
+        let task_uid = "task_uuid_stix";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let stix_bundle = json!({"type": "bundle", "id": "bundle--1", "objects": []}).to_string();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .match_header("Accept", "application/stix+json")
+            .with_status(200)
+            .with_body(&stix_bundle)
+            .create();
+        let dtl = common::create_datalake();
+
+        let result = download_bulk_search_as(&dtl, task_uid.to_string(), ExportFormat::Stix).unwrap();
+
+        token_mock.assert();
+        download_mock.assert();
+        assert_eq!(result, stix_bundle);
+    }
+
+    #[test]
+    fn test_bulk_search_download_json() {
+        let task_uid = "task_uuid_json";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .match_header("Accept", "application/json")
+            .with_status(200)
+            .with_body(json!({
+                "count": 2,
+                "results": [
+                    {"atom_value": "8.8.8.8", "atom_type": "ip", "threat_hashkey": "6c93d1a6a4cd4b5a8f3ba0ad7e3e7e6b", "tags": ["dns"]},
+                    {"atom_value": "probes.site", "atom_type": "domain"},
+                ]
+            }).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let records = download_bulk_search_json(&dtl, task_uid.to_string()).unwrap();
+
+        token_mock.assert();
+        download_mock.assert();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].atom_type, Some(AtomType::Ip));
+        assert_eq!(records[0].threat_hashkey.as_deref(), Some("6c93d1a6a4cd4b5a8f3ba0ad7e3e7e6b"));
+        assert_eq!(records[0].extra["tags"], json!(["dns"]));
+        assert_eq!(records[1].atom_value.as_deref(), Some("probes.site"));
+        assert_eq!(records[1].threat_hashkey, None);
+    }
+
+    #[test]
+    fn test_bulk_search_download_json_not_as_expected() {
+        let task_uid = "task_uuid_bad_json";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(200)
+            .with_body("atom_value\n8.8.8.8\n")
+            .create();
+        let dtl = common::create_datalake();
+
+        let err = download_bulk_search_json(&dtl, task_uid.to_string()).unwrap_err();
+
+        token_mock.assert();
+        download_mock.assert();
+        assert_eq!(err.to_string(), "API Error bulk search JSON result not as expected");
+    }
+
+    #[test]
+    fn test_start_bulk_search_as_json_wait() {
+        let task_uid = "task_uuid_json_handle";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_mock = mock("POST", "/mrti/bulk-search/")
+            .match_header("Accept", "application/json")
+            .with_status(200)
+            .with_body(json!({"task_uuid": task_uid}).to_string())
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(task_json(task_uid, "DONE"))
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .match_header("Accept", "application/json")
+            .with_status(200)
+            .with_body(json!([{"atom_value": "8.8.8.8"}]).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let handle = dtl.start_bulk_search_as(
+            "query_hash123".to_string(), vec!["atom_value".to_string()], ExportFormat::Json,
+        ).unwrap();
+        let records = handle.wait_records_with(|_| {}).unwrap();
+
+        assert_eq!(handle.format(), ExportFormat::Json);
+        assert_eq!(records[0].atom_value.as_deref(), Some("8.8.8.8"));
+        token_mock.assert();
+        bulk_search_mock.assert();
+        bulk_search_task_mock.assert();
+        download_mock.assert();
+    }
+
+    /// Clock jumping forward instead of sleeping, recording every sleep
+    #[derive(Debug)]
+    struct FakeClock {
+        now: Mutex<DateTime<Utc>>,
+        sleeps: Mutex<Vec<StdDuration>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Arc<Self> {
+            Arc::new(FakeClock {
+                now: Mutex::new(Utc.with_ymd_and_hms(2022, 8, 22, 7, 12, 0).unwrap()),
+                sleeps: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn slept_secs(&self) -> Vec<u64> {
+            self.sleeps.lock().unwrap().iter().map(StdDuration::as_secs).collect()
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: StdDuration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+            *self.now.lock().unwrap() += Duration::from_std(duration).unwrap();
+            self.sleeps.lock().unwrap().push(duration);
+            Box::pin(future::ready(()))
+        }
+    }
+
+    fn datalake_with_clock(setting: DatalakeSetting, clock: Arc<FakeClock>) -> Datalake {
+        Datalake::builder()
+            .credentials("username".to_string(), "password".to_string())
+            .setting(setting)
+            .clock(clock)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_bulk_search_exponential_polling() {
+        let task_uid = "task_uuid_exponential";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let in_progress_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(task_json(task_uid, "IN_PROGRESS"))
+            .expect(4)
+            .create();
+        let done_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(task_json(task_uid, "DONE"))
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(200)
+            .with_body("some bulk search csv result")
+            .create();
+        let mut setting = common::mock_setting();
+        setting.bulk_search_retry_interval_sec = 1;
+        setting.bulk_search_timeout_sec = 3600;
+        setting.bulk_search_polling = PollingStrategy::Exponential { multiplier: 2.0, max_interval_sec: 5 };
+        let clock = FakeClock::new();
+        let dtl = datalake_with_clock(setting, clock.clone());
+
+        let result = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string()).wait_with(|_| {}).unwrap();
+
+        assert_eq!(result, "some bulk search csv result");
+        assert_eq!(clock.slept_secs(), [1, 2, 4, 5, 5]);
+        token_mock.assert();
+        in_progress_mock.assert();
+        done_mock.assert();
+        download_mock.assert();
+    }
+
+    #[test]
+    fn test_bulk_search_eta_aware_polling() {
+        let task_uid = "task_uuid_eta";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let queued_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(json!({"count": 1, "results": [{
+                "created_at": "2022-08-22T07:11:32Z",
+                "eta": "2022-08-22T07:13:10Z",  // 1 minute after the first poll
+                "state": "QUEUED",
+                "uuid": task_uid,
+            }]}).to_string())
+            .expect(1)
+            .create();
+        let done_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(task_json(task_uid, "DONE"))
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(200)
+            .with_body("some bulk search csv result")
+            .create();
+        let mut setting = common::mock_setting();
+        setting.bulk_search_retry_interval_sec = 10;
+        setting.bulk_search_timeout_sec = 3600;
+        setting.bulk_search_polling = PollingStrategy::EtaAware { max_interval_sec: 300 };
+        let clock = FakeClock::new();
+        let dtl = datalake_with_clock(setting, clock.clone());
+
+        let mut polled_states = Vec::new();
+        let result = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string())
+            .wait_with(|task| polled_states.push(task.state.clone()))
+            .unwrap();
+
+        assert_eq!(result, "some bulk search csv result");
+        assert_eq!(polled_states, [State::QUEUED, State::DONE]);
+        assert_eq!(clock.slept_secs(), [10, 60]);
+        token_mock.assert();
+        queued_mock.assert();
+        done_mock.assert();
+        download_mock.assert();
+    }
+
+    #[test]
+    fn test_bulk_search_timeout_with_fake_clock() {
+        let task_uid = "task_uuid_fake_timeout";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let in_progress_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(task_json(task_uid, "IN_PROGRESS"))
+            .expect(3)
+            .create();
+        let mut setting = common::mock_setting();
+        setting.bulk_search_retry_interval_sec = 10;
+        setting.bulk_search_timeout_sec = 25;
+        let clock = FakeClock::new();
+        let dtl = datalake_with_clock(setting, clock.clone());
+
+        let err = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string()).wait_with(|_| {}).unwrap_err();
+
+        assert_eq!(err.to_string(), "Timeout Error Bulk search is not finished after 25 seconds");
+        assert_eq!(clock.slept_secs(), [10, 10, 10]);
+        token_mock.assert();
+        in_progress_mock.assert();
+    }
+
+    #[test]
+    fn test_bulk_search_download_backoff_with_fake_clock() {
+        let task_uid = "task_uuid_download_backoff";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .with_status(200)
+            .with_body(task_json_with_file_size(task_uid, "DONE", Some(27)))
+            .create();
+        let server_error_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(503)
+            .expect(2)
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .with_status(200)
+            .with_body("some bulk search csv result")
+            .create();
+        let mut setting = common::mock_setting();
+        setting.bulk_search_retry_interval_sec = 10;
+        let clock = FakeClock::new();
+        let dtl = datalake_with_clock(setting, clock.clone());
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("result.csv");
+
+        let bytes_written = BulkSearchHandle::from_uuid(&dtl, task_uid.to_string()).wait_to_file(&output_path, |_| {}).unwrap();
+
+        assert_eq!(bytes_written, 27);
+        assert_eq!(clock.slept_secs(), [10, 10, 20]);  // Before the first poll, then before each download retry
+        token_mock.assert();
+        bulk_search_task_mock.assert();
+        server_error_mock.assert();
+        download_mock.assert();
+    }
+
+    #[test]
+    fn test_bulk_search_create_task_with_unknown_query_field() {
+        let bulk_search_mock = mock("POST", "/mrti/bulk-search/").expect(0).create();
+        let dtl = common::create_datalake();
+
+        let err = create_bulk_search_task(&dtl, "query_hash123".to_string(), vec!["atom_valeu".to_string()]).unwrap_err();
+
+        bulk_search_mock.assert();
+        let ParseError(detailed_err) = err else { panic!("Unexpected error {err:?}") };
+        assert_eq!(detailed_err.summary, "unknown bulk search query field: atom_valeu");
+    }
+
+    #[test]
+    fn test_bulk_search_records_from_task_query_fields() {
+        let task_uid = "task_uuid_records";
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let bulk_search_mock = mock("POST", "/mrti/bulk-search/")
+            .match_body(Json(json!({
+                "query_hash": "query_hash123",
+                "query_fields": ["atom_value", "threat_hashkey", "malware.score.risk"],
+            })))
+            .with_status(200)
+            .with_body(json!({"task_uuid": task_uid}).to_string())
+            .create();
+        let bulk_search_task_mock = mock("POST", "/mrti/bulk-search/tasks/")
+            .match_body(Json(json!({"task_uuid": task_uid})))
+            .with_status(200)
+            .with_body(json!({"count": 1, "results": [{
+                "bulk_search": {
+                    "query_hash": "query_hash123",
+                    "query_fields": ["atom_value", "threat_hashkey", "malware.score.risk"],
+                },
+                "created_at": "2022-08-22T07:11:32Z",
+                "state": "DONE",
+                "uuid": task_uid,
+            }]}).to_string())
+            .create();
+        let download_mock = mock("GET", format!("/mrti/bulk-search/task/{task_uid}").as_str())
+            .match_header("Accept", "text/csv")
+            .with_status(200)
+            .with_body("atom_value,threat_hashkey,malware.score.risk\n8.8.8.8,6c93d1a6a4cd4b5a8f3ba0ad7e3e7e6b,12\n")
+            .create();
+        let dtl = common::create_datalake();
+        let query_fields = [QueryField::AtomValue, QueryField::ThreatHashkey, QueryField::Score(ThreatType::Malware)];
+
+        let handle = dtl.start_bulk_search("query_hash123".to_string(), query_fields.map(String::from).to_vec()).unwrap();
+        let records = handle.wait_records_with(|_| {}).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].atom_value.as_deref(), Some("8.8.8.8"));
+        assert_eq!(records[0].threat_hashkey.as_deref(), Some("6c93d1a6a4cd4b5a8f3ba0ad7e3e7e6b"));
+        assert_eq!(records[0].extra["malware.score.risk"], json!(12));
+        token_mock.assert();
+        bulk_search_mock.assert();
+        bulk_search_task_mock.assert();
+        download_mock.assert();
+    }
 }
@@ -8,6 +8,7 @@ mod tests {
     use lazy_static::lazy_static;
     use mockito::mock;
     use reqwest::StatusCode;
+    use reqwest::header::{HeaderMap, HeaderValue};
     use ocd_datalake_rs::{Datalake, DatalakeSetting};
     use ocd_datalake_rs::error::DatalakeError::AuthenticationError;
     use crate::common;
@@ -22,7 +23,7 @@ mod tests {
             .with_status(200)
             .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let token = dtl.get_access_token();
 
@@ -37,7 +38,7 @@ mod tests {
             let _mutex = WORKDIR_MUTEX.lock().unwrap();  // the file is a shared resource
             fs::read_to_string(example_filename).unwrap()
         };
-        let mut dtl = Datalake::new(
+        let dtl = Datalake::new(
             Some("username".to_string()),
             Some("password".to_string()),
             None,
@@ -54,7 +55,7 @@ mod tests {
             .with_status(200)
             .with_body(r#"503 serveur unavailable"#)
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let err = dtl.get_access_token().err().unwrap();
         assert_eq!(err.to_string(), "Parse Error error decoding response body");
@@ -69,7 +70,7 @@ mod tests {
             .with_status(api_status_code as usize)
             .with_body(api_response)
             .create();
-        let mut dtl = common::create_datalake();
+        let dtl = common::create_datalake();
 
         let err = dtl.get_access_token().err().unwrap();
         assert_eq!(err.to_string(), "Authentication Error Invalid credentials");
@@ -82,6 +83,44 @@ mod tests {
         token_mock.assert();
     }
 
+    #[test]
+    fn test_tokens_redacted_in_auth_error() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123", "message": "refresh token missing"}"#)
+            .create();
+        let dtl = common::create_datalake();
+
+        let err = dtl.get_access_token().err().unwrap();
+        if let AuthenticationError(detailed_err) = err {
+            assert_eq!(detailed_err.api_response.unwrap(), r#"{"access_token":"[REDACTED]","message":"refresh token missing"}"#);
+        } else {
+            panic!("Unexpected error!")
+        }
+        token_mock.assert();
+    }
+
+    #[test]
+    fn test_secrets_redacted_in_debug() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "access_123","refresh_token": "refresh_456"}"#)
+            .create();
+        let dtl = Datalake::builder()
+            .credentials("my_username".to_string(), "my_password".to_string())
+            .setting(common::mock_setting())
+            .build()
+            .unwrap();
+        dtl.get_access_token().unwrap();
+
+        let debug = format!("{dtl:?}");
+        for secret in ["my_username", "my_password", "access_123", "refresh_456"] {
+            assert!(!debug.contains(secret), "{secret} found in {debug}");
+        }
+        assert!(debug.contains("[REDACTED]"));
+        token_mock.assert();
+    }
+
     /// Check config is not dependant to the workdir
     #[test]
     fn test_default_datalake_on_another_workdir() {
@@ -128,4 +167,47 @@ mod tests {
             DatalakeSetting::new(contents.as_str()),
         ).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_builder_user_agent() {
+        let token_mock = mock("POST", "/auth/token/")
+            .match_header("user-agent", "soc-enrichment/1.0")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let mut setting = DatalakeSetting::prod();
+        setting.set_base_url(mockito::server_url());
+        let dtl = Datalake::builder()
+            .credentials("username".to_string(), "password".to_string())
+            .setting(setting)
+            .user_agent("soc-enrichment/1.0".to_string())
+            .build()
+            .unwrap();
+
+        assert_eq!(dtl.get_access_token().unwrap(), "Token 123");
+        token_mock.assert();
+    }
+
+    #[test]
+    fn test_builder_custom_http_client() {
+        let token_mock = mock("POST", "/auth/token/")
+            .match_header("x-custom-header", "custom")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-custom-header", HeaderValue::from_static("custom"));
+        let http_client = reqwest::Client::builder().default_headers(headers).build().unwrap();
+        let mut setting = DatalakeSetting::prod();
+        setting.set_base_url(mockito::server_url());
+        let dtl = Datalake::builder()
+            .credentials("username".to_string(), "password".to_string())
+            .setting(setting)
+            .http_client(http_client)
+            .build()
+            .unwrap();
+
+        assert_eq!(dtl.get_access_token().unwrap(), "Token 123");
+        token_mock.assert();
+    }
+}
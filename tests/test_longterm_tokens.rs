@@ -0,0 +1,138 @@
+#[path = "common.rs"]
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use mockito::Matcher::{AllOf, Json, UrlEncoded};
+    use mockito::mock;
+    use serde_json::json;
+    use ocd_datalake_rs::error::DatalakeError::ApiError;
+    use crate::common;
+
+    fn token_mock() -> mockito::Mock {
+        mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create()
+    }
+
+    fn longterm_token_json(id: u64, expires_at: Option<&str>) -> serde_json::Value {
+        json!({"id": id, "description": format!("token {id}"), "created_at": "2026-01-01T00:00:00Z", "expires_at": expires_at})
+    }
+
+    #[test]
+    fn test_create_longterm_token() {
+        let token_mock = token_mock();
+        let create_mock = mock("POST", "/auth/long-term-tokens/")
+            .match_header("Authorization", "Token 123")
+            .match_body(Json(json!({"description": "ci rotation", "expires_at": "2026-12-31T00:00:00Z"})))
+            .with_status(201)
+            .with_body(json!({
+                "id": 7,
+                "description": "ci rotation",
+                "created_at": "2026-10-18T00:00:00Z",
+                "expires_at": "2026-12-31T00:00:00Z",
+                "token": "new_longterm_token",
+            }).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let expires_at = DateTime::parse_from_rfc3339("2026-12-31T00:00:00Z").unwrap().to_utc();
+        let created = dtl.create_longterm_token("ci rotation", Some(expires_at)).unwrap();
+
+        assert_eq!(created.info.id, 7);
+        assert_eq!(created.info.expires_at, Some(expires_at));
+        assert_eq!(created.token.expose(), "new_longterm_token");
+        assert!(!format!("{created:?}").contains("new_longterm_token"));
+        token_mock.assert();
+        create_mock.assert();
+    }
+
+    #[test]
+    fn test_create_longterm_token_unexpected_response_is_redacted() {
+        let token_mock = token_mock();
+        let create_mock = mock("POST", "/auth/long-term-tokens/")
+            .with_status(201)
+            .with_body(r#"{"token": "new_longterm_token"}"#)
+            .create();
+        let dtl = common::create_datalake();
+
+        let err = dtl.create_longterm_token("ci rotation", None).unwrap_err();
+
+        let ApiError(detailed_err) = err else { panic!("Unexpected error {err:?}") };
+        assert_eq!(detailed_err.summary, "long-term token API response not as expected");
+        assert_eq!(detailed_err.api_response.unwrap(), r#"{"token":"[REDACTED]"}"#);
+        token_mock.assert();
+        create_mock.assert();
+    }
+
+    #[test]
+    fn test_list_longterm_tokens_over_pages() {
+        let token_mock = token_mock();
+        let first_page_mock = mock("GET", "/auth/long-term-tokens/")
+            .match_query(AllOf(vec![UrlEncoded("limit".into(), "100".into()), UrlEncoded("offset".into(), "0".into())]))
+            .with_status(200)
+            .with_body(json!({
+                "count": 101,
+                "results": (1..=100).map(|id| longterm_token_json(id, None)).collect::<Vec<_>>(),
+            }).to_string())
+            .create();
+        let second_page_mock = mock("GET", "/auth/long-term-tokens/")
+            .match_query(AllOf(vec![UrlEncoded("limit".into(), "100".into()), UrlEncoded("offset".into(), "100".into())]))
+            .with_status(200)
+            .with_body(json!({"count": 101, "results": [longterm_token_json(101, Some("2026-06-01T00:00:00Z"))]}).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let tokens = dtl.list_longterm_tokens().unwrap();
+
+        assert_eq!(tokens.len(), 101);
+        let now = DateTime::parse_from_rfc3339("2026-10-18T00:00:00Z").unwrap().to_utc();
+        let expired: Vec<u64> = tokens.iter().filter(|token| token.is_expired_at(now)).map(|token| token.id).collect();
+        assert_eq!(expired, vec![101]);
+        token_mock.assert();
+        first_page_mock.assert();
+        second_page_mock.assert();
+    }
+
+    #[test]
+    fn test_revoke_longterm_token() {
+        let token_mock = token_mock();
+        let revoke_mock = mock("DELETE", "/auth/long-term-tokens/7/")
+            .match_header("Authorization", "Token 123")
+            .with_status(204)
+            .create();
+        let missing_mock = mock("DELETE", "/auth/long-term-tokens/8/")
+            .with_status(404)
+            .with_body(r#"{"detail": "Not found."}"#)
+            .create();
+        let dtl = common::create_datalake();
+
+        dtl.revoke_longterm_token(7).unwrap();
+        let err = dtl.revoke_longterm_token(8).unwrap_err();
+
+        assert_eq!(err.to_string(), "API Error long-term token 8 could not be revoked, error code 404 Not Found");
+        token_mock.assert();
+        revoke_mock.assert();
+        missing_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_async_list_longterm_tokens() {
+        let token_mock = token_mock();
+        let list_mock = mock("GET", "/auth/long-term-tokens/")
+            .match_query(UrlEncoded("offset".into(), "0".into()))
+            .with_status(200)
+            .with_body(json!({"count": 1, "results": [longterm_token_json(1, None)]}).to_string())
+            .create();
+        let dtl = common::create_async_datalake();
+
+        let tokens = dtl.list_longterm_tokens().await.unwrap();
+
+        assert_eq!(tokens[0].description, "token 1");
+        assert_eq!(tokens[0].expires_at, None);
+        token_mock.assert();
+        list_mock.assert();
+    }
+}
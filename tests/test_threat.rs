@@ -0,0 +1,148 @@
+#[path = "common.rs"]
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use mockito::Matcher::{AllOf, UrlEncoded};
+    use mockito::mock;
+    use reqwest::StatusCode;
+    use serde_json::json;
+    use ocd_datalake_rs::atom::AtomType;
+    use ocd_datalake_rs::threat::ThreatType;
+    use ocd_datalake_rs::error::DatalakeError::ApiError;
+    use crate::common;
+
+    fn threat_json() -> serde_json::Value {
+        json!({
+            "atom_type": "domain",
+            "atom_value": "jeithe7eijeefohch3qu.probes.site",
+            "threat_found": true,
+            "events_number": 11,
+            "first_seen": "2020-11-25T21:11:41Z",
+            "last_updated": "2021-05-09T05:53:40Z",
+            "hashkey": "570c18ccf35a7003789f4332cb63bfce",
+            "href_threat": "https://datalake.cert.orangecyberdefense.com/api/v3/mrti/threats/570c18ccf35a7003789f4332cb63bfce/",
+            "scores": [
+                {"score": {"reliability": 16, "risk": 12}, "threat_type": "hack"},
+                {"score": {"reliability": 16, "risk": 13}, "threat_type": "malware"}
+            ],
+            "sources": [{
+                "count": 2,
+                "first_seen": "2020-11-25T21:11:41Z",
+                "last_updated": "2021-05-09T05:53:40Z",
+                "max_depth": 1,
+                "max_score": 13,
+                "min_depth": 1,
+                "min_score": 12,
+                "source_id": "some_source"
+            }],
+            "tags": ["probes", "scanner"],
+            "threat_types": ["hack", "malware"]
+        })
+    }
+
+    #[test]
+    fn test_lookup() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let lookup_mock = mock("GET", "/mrti/threats/lookup/")
+            .match_query(AllOf(vec![
+                UrlEncoded("atom_value".into(), "jeithe7eijeefohch3qu.probes.site".into()),
+                UrlEncoded("atom_type".into(), "domain".into()),
+                UrlEncoded("hashkey_only".into(), "false".into()),
+            ]))
+            .match_header("Authorization", "Token 123")
+            .with_status(200)
+            .with_body(threat_json().to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let threat = dtl.lookup("jeithe7eijeefohch3qu.probes.site", AtomType::Domain).unwrap();
+
+        token_mock.assert();
+        lookup_mock.assert();
+        assert!(threat.threat_found);
+        assert_eq!(threat.hashkey, "570c18ccf35a7003789f4332cb63bfce");
+        assert_eq!(threat.score(ThreatType::Malware), Some(13));
+        assert_eq!(threat.score(ThreatType::Phishing), None);
+        assert_eq!(threat.tags, vec!["probes", "scanner"]);
+        assert_eq!(threat.sources[0].source_id, "some_source");
+        assert_eq!(threat.first_seen, Some(Utc.with_ymd_and_hms(2020, 11, 25, 21, 11, 41).unwrap()));
+        assert_eq!(threat.sources[0].last_updated, Some(Utc.with_ymd_and_hms(2021, 5, 9, 5, 53, 40).unwrap()));
+        assert_eq!(threat.extra["events_number"], 11);
+    }
+
+    #[test]
+    fn test_lookup_not_found() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let lookup_mock = mock("GET", "/mrti/threats/lookup/")
+            .match_query(UrlEncoded("atom_value".into(), "8.8.8.8".into()))
+            .with_status(200)
+            .with_body(json!({
+                "atom_value": "8.8.8.8",
+                "hashkey": "6c93d1a6a4cd4b5a8f3ba0ad7e3e7e6b",
+                "threat_found": false
+            }).to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let threat = dtl.lookup("8.8.8.8", AtomType::Ip).unwrap();
+
+        token_mock.assert();
+        lookup_mock.assert();
+        assert!(!threat.threat_found);
+        assert!(threat.scores.is_empty());
+    }
+
+    #[test]
+    fn test_get_threat() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let threat_mock = mock("GET", "/mrti/threats/570c18ccf35a7003789f4332cb63bfce/")
+            .match_header("Authorization", "Token 123")
+            .with_status(200)
+            .with_body(threat_json().to_string())
+            .create();
+        let dtl = common::create_datalake();
+
+        let threat = dtl.get_threat("570c18ccf35a7003789f4332cb63bfce").unwrap();
+
+        token_mock.assert();
+        threat_mock.assert();
+        assert_eq!(threat.atom_type, Some(AtomType::Domain));
+        assert_eq!(threat.score(ThreatType::Hack), Some(12));
+    }
+
+    #[test]
+    fn test_get_threat_not_found() {
+        let token_mock = mock("POST", "/auth/token/")
+            .with_status(200)
+            .with_body(r#"{"access_token": "123","refresh_token": "456"}"#)
+            .create();
+        let threat_mock = mock("GET", "/mrti/threats/unknown_hashkey/")
+            .with_status(404)
+            .with_body(r#"{"message":"Not found"}"#)
+            .create();
+        let dtl = common::create_datalake();
+
+        let err = dtl.get_threat("unknown_hashkey").err().unwrap();
+
+        token_mock.assert();
+        threat_mock.assert();
+        assert_eq!(err.to_string(), "API Error threat API returned error code 404 Not Found");
+        if let ApiError(detailed_err) = err {
+            assert_eq!(detailed_err.api_status_code, Some(StatusCode::NOT_FOUND));
+            assert_eq!(detailed_err.api_response, Some(r#"{"message":"Not found"}"#.to_string()));
+        } else {
+            panic!("Unexpected error!")
+        }
+    }
+}